@@ -0,0 +1,229 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure;
+
+use query::Coord;
+use Result;
+
+/// Seconds of arc to radians.
+const ARCSEC_TO_RAD: f64 = ::std::f64::consts::PI / (180.0 * 3600.0);
+
+struct Ellipsoid {
+    a: f64,
+    f: f64,
+}
+
+const WGS84_ELLIPSOID: Ellipsoid = Ellipsoid {
+    a: 6_378_137.0,
+    f: 1.0 / 298.257_223_563,
+};
+
+const AIRY_1830: Ellipsoid = Ellipsoid {
+    a: 6_377_563.396,
+    f: 1.0 / 299.324_964_6,
+};
+
+const INTERNATIONAL_1924: Ellipsoid = Ellipsoid {
+    a: 6_378_388.0,
+    f: 1.0 / 297.0,
+};
+
+/// Seven parameter Helmert transformation to WGS84, using the position
+/// vector convention. Translations are in metres, rotations in arc seconds
+/// and scale in parts per million.
+struct Helmert {
+    tx: f64,
+    ty: f64,
+    tz: f64,
+    rx: f64,
+    ry: f64,
+    rz: f64,
+    s: f64,
+}
+
+/// Geodetic datums that coordinates can be supplied in. Coordinates are
+/// converted to WGS84, which the Directions API expects, before queries
+/// are built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Datum {
+    Wgs84,
+    Gda94,
+    Osgb36,
+    Ed50,
+}
+
+impl Datum {
+    fn parameters(self) -> Option<(Ellipsoid, Helmert)> {
+        match self {
+            // GDA94 and WGS84 agree to within the accuracy of WGS84 itself,
+            // so no transformation is applied (EPSG:1150).
+            Datum::Wgs84 | Datum::Gda94 => None,
+            // Ordnance Survey published parameters (EPSG:1314).
+            Datum::Osgb36 => Some((
+                AIRY_1830,
+                Helmert {
+                    tx: 446.448,
+                    ty: -125.157,
+                    tz: 542.060,
+                    rx: 0.1502,
+                    ry: 0.2470,
+                    rz: 0.8421,
+                    s: -20.4894,
+                },
+            )),
+            // Mean for Western Europe (EPSG:1133).
+            Datum::Ed50 => Some((
+                INTERNATIONAL_1924,
+                Helmert {
+                    tx: -87.0,
+                    ty: -98.0,
+                    tz: -121.0,
+                    rx: 0.0,
+                    ry: 0.0,
+                    rz: 0.0,
+                    s: 0.0,
+                },
+            )),
+        }
+    }
+
+    /// Convert a coordinate in this datum to WGS84.
+    pub fn to_wgs84(self, coord: &Coord) -> Coord {
+        let (ellipsoid, helmert) = match self.parameters() {
+            Some(parameters) => parameters,
+            None => {
+                return Coord {
+                    lat: coord.lat,
+                    lon: coord.lon,
+                }
+            }
+        };
+
+        let (x, y, z) = to_cartesian(coord, &ellipsoid);
+
+        let s = 1.0 + helmert.s * 1e-6;
+        let rx = helmert.rx * ARCSEC_TO_RAD;
+        let ry = helmert.ry * ARCSEC_TO_RAD;
+        let rz = helmert.rz * ARCSEC_TO_RAD;
+        let x2 = helmert.tx + s * (x - rz * y + ry * z);
+        let y2 = helmert.ty + s * (rz * x + y - rx * z);
+        let z2 = helmert.tz + s * (-ry * x + rx * y + z);
+
+        from_cartesian(x2, y2, z2, &WGS84_ELLIPSOID)
+    }
+}
+
+fn to_cartesian(coord: &Coord, ellipsoid: &Ellipsoid) -> (f64, f64, f64) {
+    let lat = coord.lat.to_radians();
+    let lon = coord.lon.to_radians();
+    let e2 = ellipsoid.f * (2.0 - ellipsoid.f);
+    let nu = ellipsoid.a / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+
+    (
+        nu * lat.cos() * lon.cos(),
+        nu * lat.cos() * lon.sin(),
+        nu * (1.0 - e2) * lat.sin(),
+    )
+}
+
+fn from_cartesian(x: f64, y: f64, z: f64, ellipsoid: &Ellipsoid) -> Coord {
+    let e2 = ellipsoid.f * (2.0 - ellipsoid.f);
+    let p = (x * x + y * y).sqrt();
+
+    // Iterate on latitude until it converges to well below the precision
+    // used when displaying coordinates.
+    let mut lat = z.atan2(p * (1.0 - e2));
+    for _ in 0..10 {
+        let nu = ellipsoid.a / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+        let next = (z + e2 * nu * lat.sin()).atan2(p);
+        if (next - lat).abs() < 1e-12 {
+            lat = next;
+            break;
+        }
+        lat = next;
+    }
+
+    Coord {
+        lat: lat.to_degrees(),
+        lon: y.atan2(x).to_degrees(),
+    }
+}
+
+impl FromStr for Datum {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "wgs84" => Ok(Datum::Wgs84),
+            "gda94" => Ok(Datum::Gda94),
+            "osgb36" => Ok(Datum::Osgb36),
+            "ed50" => Ok(Datum::Ed50),
+            _ => Err(DatumError::Unsupported { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for Datum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            Datum::Wgs84 => "wgs84",
+            Datum::Gda94 => "gda94",
+            Datum::Osgb36 => "osgb36",
+            Datum::Ed50 => "ed50",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+#[derive(Debug, Fail)]
+enum DatumError {
+    #[fail(
+        display = "no transformation to WGS84 is available for datum ({}), supported datums are wgs84, gda94, osgb36 and ed50",
+        unk
+    )]
+    Unsupported { unk: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datum() {
+        assert_eq!(Datum::Wgs84, "wgs84".parse().unwrap());
+        assert_eq!(Datum::Gda94, "GDA94".parse().unwrap());
+        assert_eq!(Datum::Osgb36, "osgb36".parse().unwrap());
+        assert_eq!(Datum::Ed50, "ed50".parse().unwrap());
+        assert!("nad27".parse::<Datum>().is_err());
+    }
+
+    #[test]
+    fn test_identity_datums() {
+        let coord = Coord::new(-37.820189, 145.149954).unwrap();
+        assert_eq!(coord, Datum::Wgs84.to_wgs84(&coord));
+        assert_eq!(coord, Datum::Gda94.to_wgs84(&coord));
+    }
+
+    #[test]
+    fn test_cartesian_round_trip() {
+        let coord = Coord::new(-37.820189, 145.149954).unwrap();
+        let (x, y, z) = to_cartesian(&coord, &WGS84_ELLIPSOID);
+        let res = from_cartesian(x, y, z, &WGS84_ELLIPSOID);
+
+        assert!((coord.lat - res.lat).abs() < 1e-9);
+        assert!((coord.lon - res.lon).abs() < 1e-9);
+    }
+
+    /// The Greenwich meridian (OSGB36 longitude zero) lies roughly 100
+    /// metres west of the WGS84 prime meridian.
+    #[test]
+    fn test_osgb36_to_wgs84() {
+        let coord = Coord::new(51.477811, 0.0).unwrap();
+        let res = Datum::Osgb36.to_wgs84(&coord);
+
+        assert!((res.lon - -0.001_6).abs() < 0.000_2);
+        assert!((res.lat - coord.lat).abs() < 0.001);
+    }
+}
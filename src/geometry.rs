@@ -0,0 +1,200 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure;
+use failure::ResultExt;
+use serde_json;
+use serde_json::Value;
+
+use query::Coord;
+use Result;
+
+/// Formats in which a point geometry can be supplied in a single column.
+/// Both list the longitude before the latitude.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeometryFormat {
+    Wkt,
+    GeoJson,
+}
+
+impl GeometryFormat {
+    /// Parse a point geometry into a coordinate.
+    pub fn parse_point(self, inp: &str) -> Result<Coord> {
+        let (lon, lat) = match self {
+            GeometryFormat::Wkt => parse_wkt(inp)?,
+            GeometryFormat::GeoJson => parse_geojson(inp)?,
+        };
+        Coord::new(lat, lon)
+    }
+}
+
+impl FromStr for GeometryFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wkt" => Ok(GeometryFormat::Wkt),
+            "geojson" => Ok(GeometryFormat::GeoJson),
+            _ => Err(GeometryError::UnknownFormat { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for GeometryFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            GeometryFormat::Wkt => "wkt",
+            GeometryFormat::GeoJson => "geojson",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+/// Parse a WKT point, e.g. "POINT(145.15 -37.82)", into a longitude and
+/// latitude. An EWKT SRID prefix, as exported by PostGIS, is ignored.
+fn parse_wkt(inp: &str) -> Result<(f64, f64)> {
+    let invalid = || GeometryError::InvalidWkt {
+        inv: inp.to_string(),
+    };
+
+    let mut wkt = inp.trim();
+    if wkt.to_uppercase().starts_with("SRID=") {
+        wkt = wkt.splitn(2, ';').nth(1).ok_or_else(invalid)?.trim();
+    }
+
+    let open = wkt.find('(').ok_or_else(invalid)?;
+    let geometry_type = wkt[..open].trim().to_uppercase();
+    if geometry_type != "POINT" {
+        Err(GeometryError::NotPoint { typ: geometry_type })?
+    }
+    if !wkt.ends_with(')') {
+        Err(invalid())?
+    }
+
+    let values = wkt[open + 1..wkt.len() - 1]
+        .split_whitespace()
+        .map(|v| v.parse::<f64>())
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .context(invalid())?;
+    match values.as_slice() {
+        [lon, lat] => Ok((*lon, *lat)),
+        _ => Err(invalid())?,
+    }
+}
+
+/// Parse a GeoJSON point geometry, e.g.
+/// '{"type": "Point", "coordinates": [145.15, -37.82]}', into a longitude
+/// and latitude. A feature wrapping a point geometry is also accepted.
+fn parse_geojson(inp: &str) -> Result<(f64, f64)> {
+    let invalid = || GeometryError::InvalidGeoJson {
+        inv: inp.to_string(),
+    };
+
+    let value = serde_json::from_str::<Value>(inp).context(invalid())?;
+    let geometry = match value["type"].as_str() {
+        Some("Feature") => &value["geometry"],
+        _ => &value,
+    };
+
+    match geometry["type"].as_str() {
+        Some("Point") => (),
+        Some(typ) => Err(GeometryError::NotPoint {
+            typ: typ.to_string(),
+        })?,
+        None => Err(invalid())?,
+    }
+
+    let coordinates = geometry["coordinates"].as_array().ok_or_else(invalid)?;
+    match (coordinates.get(0), coordinates.get(1)) {
+        (Some(lon), Some(lat)) if coordinates.len() == 2 => Ok((
+            lon.as_f64().ok_or_else(invalid)?,
+            lat.as_f64().ok_or_else(invalid)?,
+        )),
+        _ => Err(invalid())?,
+    }
+}
+
+#[derive(Debug, Fail)]
+enum GeometryError {
+    #[fail(display = "unrecognised geometry format ({})", unk)]
+    UnknownFormat { unk: String },
+    #[fail(display = "geometry must be a point ({})", typ)]
+    NotPoint { typ: String },
+    #[fail(display = "invalid WKT point supplied ({})", inv)]
+    InvalidWkt { inv: String },
+    #[fail(display = "invalid GeoJSON point supplied ({})", inv)]
+    InvalidGeoJson { inv: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_geometry_format() {
+        assert_eq!(GeometryFormat::Wkt, "wkt".parse().unwrap());
+        assert_eq!(GeometryFormat::GeoJson, "geojson".parse().unwrap());
+        assert!("kml".parse::<GeometryFormat>().is_err());
+    }
+
+    /// WKT lists the longitude first, the reverse of the Directions API.
+    #[test]
+    fn test_parse_wkt() {
+        let exp = Coord::new(-37.82, 145.15).unwrap();
+
+        assert_eq!(exp, GeometryFormat::Wkt.parse_point("POINT(145.15 -37.82)").unwrap());
+        assert_eq!(exp, GeometryFormat::Wkt.parse_point(" point ( 145.15  -37.82 ) ").unwrap());
+        assert_eq!(
+            exp,
+            GeometryFormat::Wkt.parse_point("SRID=4326;POINT(145.15 -37.82)").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_wkt_invalid() {
+        assert!(GeometryFormat::Wkt.parse_point("LINESTRING(145.15 -37.82, 145 -37)").is_err());
+        assert!(GeometryFormat::Wkt.parse_point("POINT(145.15)").is_err());
+        assert!(GeometryFormat::Wkt.parse_point("POINT(145.15 -37.82").is_err());
+        assert!(GeometryFormat::Wkt.parse_point("POINT(a b)").is_err());
+        assert!(GeometryFormat::Wkt.parse_point("-37.82,145.15").is_err());
+        // Swapped coordinates put the latitude out of bounds
+        assert!(GeometryFormat::Wkt.parse_point("POINT(-37.82 145.15)").is_err());
+    }
+
+    /// GeoJSON lists the longitude first, the reverse of the Directions API.
+    #[test]
+    fn test_parse_geojson() {
+        let exp = Coord::new(-37.82, 145.15).unwrap();
+
+        assert_eq!(
+            exp,
+            GeometryFormat::GeoJson
+                .parse_point(r#"{"type": "Point", "coordinates": [145.15, -37.82]}"#)
+                .unwrap()
+        );
+        assert_eq!(
+            exp,
+            GeometryFormat::GeoJson
+                .parse_point(
+                    r#"{"type": "Feature", "geometry": {"type": "Point", "coordinates": [145.15, -37.82]}}"#
+                ).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_geojson_invalid() {
+        let format = GeometryFormat::GeoJson;
+
+        assert!(format
+            .parse_point(r#"{"type": "LineString", "coordinates": [[145.15, -37.82]]}"#)
+            .is_err());
+        assert!(format.parse_point(r#"{"type": "Point", "coordinates": [145.15]}"#).is_err());
+        assert!(format.parse_point(r#"{"coordinates": [145.15, -37.82]}"#).is_err());
+        assert!(format.parse_point("POINT(145.15 -37.82)").is_err());
+        // Swapped coordinates put the latitude out of bounds
+        assert!(format
+            .parse_point(r#"{"type": "Point", "coordinates": [-37.82, 145.15]}"#)
+            .is_err());
+    }
+}
@@ -1,13 +1,21 @@
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
 
 use chrono::NaiveDateTime;
 use csv;
+use flate2::read::GzDecoder;
 
+use failure;
 use failure::ResultExt;
+use serde_json;
+use serde_json::Value;
 
-use query::Query;
+use geometry::GeometryFormat;
+use query::{Avoidance, BoundingBox, Mode, Query, DEFAULT_COORD_PRECISION};
 use Result;
 
 enum Input {
@@ -24,6 +32,9 @@ impl io::Read for Input {
     }
 }
 
+/// The first two bytes of every gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 pub fn load(path: &Option<String>) -> Result<String> {
     let mut rdr = match path {
         Some(p) => Input::File(io::BufReader::new(
@@ -32,59 +43,688 @@ pub fn load(path: &Option<String>) -> Result<String> {
         None => Input::Stdin(io::stdin()),
     };
 
-    let mut buffer = String::new();
-    rdr.read_to_string(&mut buffer)
+    let mut buffer = Vec::new();
+    rdr.read_to_end(&mut buffer)
         .context(InputError::Data)?;
-    Ok(buffer.trim().to_string())
+    let gzip = path.as_ref().map_or(false, |p| has_extension(p, "gz"));
+    Ok(decode(buffer, gzip)?.trim().to_string())
+}
+
+/// Decode input as UTF-8 text, decompressing it first if it is gzipped,
+/// either as indicated by the caller or as detected from its first bytes.
+fn decode(buffer: Vec<u8>, gzip: bool) -> Result<String> {
+    if gzip || buffer.starts_with(&GZIP_MAGIC) {
+        let mut text = String::new();
+        GzDecoder::new(buffer.as_slice())
+            .read_to_string(&mut text)
+            .context(InputError::Gzip)?;
+        Ok(text)
+    } else {
+        Ok(String::from_utf8(buffer).context(InputError::Data)?)
+    }
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case(extension))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct CsvRecord {
-    pub id: String,
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    pub id: Option<String>,
+    #[serde(default)]
     pub origin_lat: String,
+    #[serde(default)]
     pub origin_lon: String,
+    #[serde(default)]
     pub destination_lat: String,
+    #[serde(default)]
     pub destination_lon: String,
-    pub departure_time: String,
+    pub origin_geometry: Option<String>,
+    pub destination_geometry: Option<String>,
+    pub departure_time: Option<String>,
+    pub arrival_time: Option<String>,
+    #[serde(default)]
     pub mode: String,
     pub avoidances: Option<String>,
     pub traffic_model: Option<String>,
+    pub waypoints: Option<String>,
+    pub units: Option<String>,
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub transit_mode: Option<String>,
+    pub transit_routing_preference: Option<String>,
+    pub alternatives: Option<String>,
+}
+
+impl Record {
+    fn field(&self, name: &str) -> Option<&str> {
+        let value = match name {
+            "origin_lat" => return Some(&self.origin_lat),
+            "origin_lon" => return Some(&self.origin_lon),
+            "destination_lat" => return Some(&self.destination_lat),
+            "destination_lon" => return Some(&self.destination_lon),
+            "origin_geometry" => &self.origin_geometry,
+            "destination_geometry" => &self.destination_geometry,
+            "departure_time" => &self.departure_time,
+            "arrival_time" => &self.arrival_time,
+            "mode" => return Some(&self.mode),
+            "avoidances" => &self.avoidances,
+            "traffic_model" => &self.traffic_model,
+            "waypoints" => &self.waypoints,
+            "units" => &self.units,
+            "language" => &self.language,
+            "region" => &self.region,
+            "transit_mode" => &self.transit_mode,
+            "transit_routing_preference" => &self.transit_routing_preference,
+            "alternatives" => &self.alternatives,
+            _ => return None,
+        };
+        value.as_ref().map(String::as_str)
+    }
+
+    fn optional_field_mut(&mut self, name: &str) -> Option<&mut Option<String>> {
+        match name {
+            "avoidances" => Some(&mut self.avoidances),
+            "traffic_model" => Some(&mut self.traffic_model),
+            "units" => Some(&mut self.units),
+            "language" => Some(&mut self.language),
+            "region" => Some(&mut self.region),
+            "transit_mode" => Some(&mut self.transit_mode),
+            "transit_routing_preference" => Some(&mut self.transit_routing_preference),
+            "alternatives" => Some(&mut self.alternatives),
+            _ => None,
+        }
+    }
+}
+
+const TEMPLATE_FIELDS: [&str; 18] = [
+    "origin_lat",
+    "origin_lon",
+    "destination_lat",
+    "destination_lon",
+    "origin_geometry",
+    "destination_geometry",
+    "departure_time",
+    "arrival_time",
+    "mode",
+    "avoidances",
+    "traffic_model",
+    "waypoints",
+    "units",
+    "language",
+    "region",
+    "transit_mode",
+    "transit_routing_preference",
+    "alternatives",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Field(String),
+}
+
+/// A template for generating ids from the fields of a record, with field
+/// names wrapped in braces, e.g. "{origin_lat},{origin_lon}@{mode}".
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdTemplate(Vec<TemplatePart>);
+
+impl IdTemplate {
+    pub fn render(&self, record: &Record) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(s) => out.push_str(s),
+                TemplatePart::Field(f) => out.push_str(record.field(f).unwrap_or("")),
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for IdTemplate {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| InputError::UnclosedPlaceholder {
+                    template: s.to_string(),
+                })?;
+            let field = &rest[start + 1..start + end];
+            if !TEMPLATE_FIELDS.contains(&field) {
+                Err(InputError::UnknownPlaceholder {
+                    unk: field.to_string(),
+                })?
+            }
+            parts.push(TemplatePart::Field(field.to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(IdTemplate(parts))
+    }
+}
+
+/// Record fields that can be given a default value, used when a record
+/// leaves the field blank.
+const DEFAULTABLE_FIELDS: [&str; 9] = [
+    "mode",
+    "avoidances",
+    "traffic_model",
+    "units",
+    "language",
+    "region",
+    "transit_mode",
+    "transit_routing_preference",
+    "alternatives",
+];
+
+/// Default values for record fields, keyed by field name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Defaults(Vec<(String, String)>);
+
+impl Defaults {
+    pub fn new(fields: Vec<(String, String)>) -> Result<Defaults> {
+        for (field, _) in &fields {
+            if !DEFAULTABLE_FIELDS.contains(&field.as_str()) {
+                Err(InputError::UnknownDefault { unk: field.clone() })?
+            }
+        }
+        Ok(Defaults(fields))
+    }
+
+    fn contains(&self, field: &str) -> bool {
+        self.0.iter().any(|(f, _)| f == field)
+    }
+
+    /// Fill any blank fields of the record that have a default.
+    fn apply(&self, record: &mut Record) {
+        for (field, value) in &self.0 {
+            if field == "mode" {
+                if record.mode.is_empty() {
+                    record.mode = value.clone();
+                }
+            } else if let Some(current) = record.optional_field_mut(field) {
+                if current.as_ref().map_or(true, |c| c.is_empty()) {
+                    *current = Some(value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Settings controlling how records are turned into queries.
+#[derive(Debug, Default)]
+pub struct ReadOptions {
+    pub id_template: Option<IdTemplate>,
+    pub geometry_format: Option<GeometryFormat>,
+    pub defaults: Defaults,
+    pub skip_invalid: bool,
+    pub delimiter: Option<u8>,
+    pub bbox: Option<BoundingBox>,
+    pub limit: Option<usize>,
+    pub no_shift: bool,
+    pub live: bool,
+    pub coord_precision: Option<usize>,
+}
+
+/// Queries read from input, along with the errors of any invalid records
+/// that were skipped rather than failing the whole input.
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub queries: Vec<Query>,
+    pub skipped: Vec<failure::Error>,
+    /// The number of queries built from each valid record, which can be
+    /// more than one when a record lists several modes.
+    sizes: Vec<usize>,
+}
+
+/// Formats that queries can be read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Json,
+}
+
+impl InputFormat {
+    /// Infer the format from the extension of the input path, falling back
+    /// to CSV.
+    pub fn from_path(path: &Option<String>) -> InputFormat {
+        // Look past a gzip extension, e.g. `queries.json.gz`.
+        let extension = path
+            .as_ref()
+            .map(|p| Path::new(p))
+            .map(|p| match p.file_stem() {
+                Some(stem) if has_extension(&p.to_string_lossy(), "gz") => Path::new(stem),
+                _ => p,
+            })
+            .and_then(|p| p.extension())
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_ref().map(String::as_str) {
+            Some("json") => InputFormat::Json,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(InputError::UnknownFormat { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            InputFormat::Csv => "csv",
+            InputFormat::Json => "json",
+        };
+        write!(f, "{}", out)
+    }
 }
 
-pub fn read_csv(inp: &str, now: &NaiveDateTime) -> Result<Vec<Query>> {
+impl Batch {
+    /// Add the queries read from a record, or the error from reading it. The
+    /// error is returned unless invalid records are being skipped.
+    fn add(&mut self, queries: Result<Vec<Query>>, options: &ReadOptions) -> Result<()> {
+        match queries {
+            Ok(queries) => {
+                self.sizes.push(queries.len());
+                self.queries.extend(queries);
+            }
+            Err(err) if options.skip_invalid => self.skipped.push(err),
+            Err(err) => Err(err)?,
+        }
+        self.truncate(options);
+        Ok(())
+    }
+
+    /// Whether the limit on the number of valid records has been reached.
+    fn is_full(&self, options: &ReadOptions) -> bool {
+        options.limit.map_or(false, |limit| self.sizes.len() >= limit)
+    }
+
+    /// Drop the queries of any valid records beyond the limit.
+    fn truncate(&mut self, options: &ReadOptions) {
+        if let Some(limit) = options.limit {
+            if self.sizes.len() > limit {
+                self.sizes.truncate(limit);
+                self.queries.truncate(self.sizes.iter().sum());
+            }
+        }
+    }
+}
+
+/// Load and read queries from each of the given paths in turn, or from STDIN
+/// if there are none. The format of each input is inferred from its path
+/// unless one is supplied. When there are several paths, errors name the
+/// file that they came from.
+pub fn read_paths(
+    paths: &[String],
+    format: Option<InputFormat>,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Batch> {
+    if paths.len() < 2 {
+        return read_path(&paths.first().cloned(), format, now, options);
+    }
+
+    let mut batch = Batch::default();
+    for path in paths {
+        if batch.is_full(options) {
+            break;
+        }
+        let file = InputError::File { path: path.clone() };
+        let file_batch =
+            read_path(&Some(path.clone()), format, now, options).context(file.clone())?;
+        batch.queries.extend(file_batch.queries);
+        batch.sizes.extend(file_batch.sizes);
+        batch.skipped.extend(
+            file_batch
+                .skipped
+                .into_iter()
+                .map(|err| err.context(file.clone()).into()),
+        );
+    }
+    batch.truncate(options);
+    Ok(batch)
+}
+
+fn read_path(
+    path: &Option<String>,
+    format: Option<InputFormat>,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Batch> {
+    let inp = load(path)?;
+    let format = format.unwrap_or_else(|| InputFormat::from_path(path));
+    read(&inp, format, now, options)
+}
+
+/// Read queries from input in the given format.
+pub fn read(
+    inp: &str,
+    format: InputFormat,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Batch> {
+    match format {
+        InputFormat::Csv => read_csv(inp, now, options),
+        InputFormat::Json => read_json(inp, now, options),
+    }
+}
+
+pub fn read_csv(
+    inp: &str,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Batch> {
     let mut rdr = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
+        .delimiter(options.delimiter.unwrap_or(b','))
         .from_reader(inp.as_bytes());
+    check_header(rdr.headers()?, options)?;
 
-    let mut queries = Vec::new();
+    let mut batch = Batch::default();
     let mut current_line = 1;
     for result in rdr.deserialize() {
-        let record: CsvRecord = result.context(InputError::Line { ln: current_line })?;
-        let query = Query::from_csv_record(record, now)
-            .context(InputError::Line { ln: current_line })?;
-        queries.push(query);
+        if batch.is_full(options) {
+            break;
+        }
+        let query = result
+            .map_err(failure::Error::from)
+            .and_then(|record: Record| to_queries(record, now, options))
+            .context(InputError::Line { ln: current_line })
+            .map_err(failure::Error::from);
+        batch.add(query, options)?;
 
         current_line += 1;
     }
 
-    Ok(queries)
+    Ok(batch)
 }
 
-#[derive(Debug, Fail)]
+/// Check the header row of CSV input before reading any records, so that a
+/// misspelled or missing column is reported as such rather than as invalid
+/// contents.
+fn check_header(header: &csv::StringRecord, options: &ReadOptions) -> Result<()> {
+    // Input without even a header row holds no queries.
+    if header.is_empty() {
+        return Ok(());
+    }
+
+    // The record fields are the id followed by the template fields.
+    let unknown = header
+        .iter()
+        .filter(|c| *c != "id" && !TEMPLATE_FIELDS.contains(c))
+        .collect::<Vec<_>>();
+
+    let mut required = Vec::new();
+    if options.id_template.is_none() {
+        required.push("id");
+    }
+    if !options.defaults.contains("mode") {
+        required.push("mode");
+    }
+    if options.geometry_format.is_some() {
+        required.extend(&["origin_geometry", "destination_geometry"]);
+    } else {
+        required.extend(&["origin_lat", "origin_lon", "destination_lat", "destination_lon"]);
+    }
+    let has = |column: &str| header.iter().any(|c| c == column);
+    let mut missing = required.into_iter().filter(|c| !has(c)).collect::<Vec<_>>();
+    if !has("departure_time") && !has("arrival_time") {
+        missing.push("departure_time");
+    }
+
+    let mut problems = Vec::new();
+    if !unknown.is_empty() {
+        problems.push(format!("unknown column(s): {}", unknown.join(", ")));
+    }
+    if !missing.is_empty() {
+        problems.push(format!("missing column(s): {}", missing.join(", ")));
+    }
+    if !problems.is_empty() {
+        Err(InputError::Header {
+            problems: problems.join("; "),
+        })?
+    }
+    Ok(())
+}
+
+/// Read queries from a JSON array of objects with the same fields as the
+/// CSV input. Numbers are accepted wherever a field holds a number.
+pub fn read_json(
+    inp: &str,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Batch> {
+    let document = serde_json::from_str::<Value>(inp).map_err(|e| InputError::Json {
+        ln: e.line(),
+        col: e.column(),
+    })?;
+    let objects = match document {
+        Value::Array(objects) => objects,
+        _ => Err(InputError::JsonNotArray)?,
+    };
+
+    let mut batch = Batch::default();
+    for (i, object) in objects.into_iter().enumerate() {
+        if batch.is_full(options) {
+            break;
+        }
+        let query = serde_json::from_value(stringify_fields(object))
+            .map_err(failure::Error::from)
+            .and_then(|record: Record| to_queries(record, now, options))
+            .context(InputError::Record { num: i + 1 })
+            .map_err(failure::Error::from);
+        batch.add(query, options)?;
+    }
+
+    Ok(batch)
+}
+
+/// Convert the number and boolean fields of a JSON object to strings, as
+/// they would appear in CSV input. Other values are left for deserialisation
+/// to reject.
+fn stringify_fields(object: Value) -> Value {
+    match object {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| match v {
+                    Value::Number(n) => (k, Value::String(n.to_string())),
+                    Value::Bool(b) => (k, Value::String(b.to_string())),
+                    v => (k, v),
+                }).collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Build queries from a record, regardless of the format it was read from.
+/// A record with several modes joined by pipes, e.g. 'driving|walking',
+/// gives a query for each mode, with the mode appended to its id.
+fn to_queries(
+    mut record: Record,
+    now: &NaiveDateTime,
+    options: &ReadOptions,
+) -> Result<Vec<Query>> {
+    options.defaults.apply(&mut record);
+    if let Some(format) = options.geometry_format {
+        read_geometries(&mut record, format)?;
+    }
+    if !record.mode.contains('|') {
+        return Ok(vec![to_query(record, now, options)?]);
+    }
+
+    record
+        .mode
+        .split('|')
+        .map(|mode| {
+            let mode = mode.trim();
+            let mut expanded = record.clone();
+            expanded.id = record
+                .id
+                .as_ref()
+                .filter(|id| !id.is_empty())
+                .map(|id| format!("{}:{}", id, mode));
+            expanded.mode = mode.to_string();
+            restrict_to_mode(&mut expanded);
+            to_query(expanded, now, options)
+        }).collect()
+}
+
+/// Drop the fields of a record expanded from several modes that only apply
+/// to other modes: the traffic model and tolls or highways avoidances for
+/// driving, indoors avoidances for walking and the transit options for
+/// transit. A mode that cannot be read is left for the query to reject.
+fn restrict_to_mode(record: &mut Record) {
+    let mode = match record.mode.parse::<Mode>() {
+        Ok(mode) => mode,
+        Err(_) => return,
+    };
+    if mode != Mode::Driving {
+        record.traffic_model = None;
+    }
+    if mode != Mode::Transit {
+        record.transit_mode = None;
+        record.transit_routing_preference = None;
+    }
+    if let Some(avoidances) = record.avoidances.take() {
+        let kept = avoidances
+            .split('|')
+            .filter(|a| a.parse::<Avoidance>().map_or(true, |a| a.supports(mode)))
+            .collect::<Vec<_>>();
+        if !kept.is_empty() {
+            record.avoidances = Some(kept.join("|"));
+        }
+    }
+}
+
+fn to_query(mut record: Record, now: &NaiveDateTime, options: &ReadOptions) -> Result<Query> {
+    // Ids are built once the record holds its coordinates and single mode
+    if let Some(template) = &options.id_template {
+        if record.id.as_ref().map_or(true, |id| id.is_empty()) {
+            record.id = Some(template.render(&record));
+        }
+    }
+
+    // Live driving queries depart now, with the traffic expected at the time
+    if options.live && record.mode == "driving" {
+        record.departure_time = Some("now".to_string());
+        record.arrival_time = None;
+        record.traffic_model = Some("best_guess".to_string());
+    }
+    let precision = options.coord_precision.unwrap_or(DEFAULT_COORD_PRECISION);
+    let query = Query::from_record(record, now, !options.no_shift, precision)?;
+    if let Some(bbox) = &options.bbox {
+        bbox.check(&query)?;
+    }
+    Ok(query)
+}
+
+/// Fill in the origin and destination coordinates of a record from its
+/// geometry fields.
+fn read_geometries(record: &mut Record, format: GeometryFormat) -> Result<()> {
+    let origin = match record.origin_geometry {
+        Some(ref geometry) => format.parse_point(geometry)?,
+        None => Err(InputError::MissingGeometry {
+            field: "origin_geometry".to_string(),
+        })?,
+    };
+    let destination = match record.destination_geometry {
+        Some(ref geometry) => format.parse_point(geometry)?,
+        None => Err(InputError::MissingGeometry {
+            field: "destination_geometry".to_string(),
+        })?,
+    };
+
+    record.origin_lat = origin.lat.to_string();
+    record.origin_lon = origin.lon.to_string();
+    record.destination_lat = destination.lat.to_string();
+    record.destination_lon = destination.lon.to_string();
+    Ok(())
+}
+
+#[derive(Clone, Debug, Fail)]
 enum InputError {
     #[fail(display = "invalid input file path supplied ({})", path)]
     Path { path: String },
+    #[fail(display = "invalid input file ({})", path)]
+    File { path: String },
     #[fail(display = "input file is not valid")]
     Data,
+    #[fail(display = "input file is not valid gzip")]
+    Gzip,
     #[fail(display = "invalid contents on line {}", ln)]
     Line { ln: i64 },
+    #[fail(display = "invalid header ({})", problems)]
+    Header { problems: String },
+    #[fail(display = "unknown id template placeholder ({})", unk)]
+    UnknownPlaceholder { unk: String },
+    #[fail(display = "unclosed placeholder in id template ({})", template)]
+    UnclosedPlaceholder { template: String },
+    #[fail(display = "unrecognised input format ({})", unk)]
+    UnknownFormat { unk: String },
+    #[fail(display = "invalid JSON on line {}, column {}", ln, col)]
+    Json { ln: usize, col: usize },
+    #[fail(display = "JSON input must be an array of objects")]
+    JsonNotArray,
+    #[fail(display = "invalid contents in record {}", num)]
+    Record { num: usize },
+    #[fail(display = "no default can be given for field ({})", unk)]
+    UnknownDefault { unk: String },
+    #[fail(display = "missing geometry field ({})", field)]
+    MissingGeometry { field: String },
 }
 
 #[cfg(test)]
 #[cfg_attr(tarpaulin, skip)]
 mod tests {
     use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use query::*;
+    use std::env;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// A scratch directory unique to this process, removed when dropped so
+    /// that it is cleaned up even if the test fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("{}_{}", name, process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
 
     #[test]
     fn test_read_valid_csv() {
@@ -95,17 +735,27 @@ mod tests {
 
         let exp = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1537308000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         };
+        let options = ReadOptions::default();
 
         assert_eq!(
             exp,
-            read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).unwrap()[0]
+            read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+                .unwrap()
+                .queries[0]
         );
     }
 
@@ -116,7 +766,8 @@ mod tests {
             -37.820189,145.149954,-37.819681,144.952302,1534284000,driving,tolls,best_guess
         ");
 
-        assert!(read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).is_err());
+        let options = ReadOptions::default();
+        assert!(read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options).is_err());
     }
 
     #[test]
@@ -126,6 +777,596 @@ mod tests {
             1,-37.820189,145.149954,-37.819681,144.952302,1534284000,driving,tolls,best_guess,unknown_value
         ");
 
-        assert!(read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).is_err());
+        let options = ReadOptions::default();
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options);
+        assert_eq!(
+            "invalid header (unknown column(s): ids, unknown_field; missing column(s): id)",
+            res.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_csv_header() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let inp = indoc!("
+            origin_lat,origin_lon,destination_lat,destination_lon,arrival_time
+            -37.820189,145.149954,-37.819681,144.952302,1534284000
+        ");
+
+        let res = read_csv(inp, &now, &ReadOptions::default());
+        assert_eq!(
+            "invalid header (missing column(s): id, mode)",
+            res.unwrap_err().to_string()
+        );
+
+        // Columns that are filled in some other way can be left out.
+        let options = ReadOptions {
+            id_template: Some("{mode}".parse().unwrap()),
+            defaults: Defaults::new(vec![("mode".to_string(), "walking".to_string())]).unwrap(),
+            ..ReadOptions::default()
+        };
+        assert_eq!(1, read_csv(inp, &now, &options).unwrap().queries.len());
+
+        let inp = "id,origin_geometry,destination_geometry,mode\n";
+        let options = ReadOptions {
+            geometry_format: Some(GeometryFormat::Wkt),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            "invalid header (missing column(s): departure_time)",
+            read_csv(inp, &now, &options).unwrap_err().to_string()
+        );
+
+        assert!(read_csv("", &now, &ReadOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_csv_skip_invalid() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            2,-37.820189,145.149954,-37.819681,144.952302,1537000000,flying
+            3,-97.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            4,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let options = ReadOptions::default();
+        assert!(read_csv(inp, &now, &options).is_err());
+
+        let options = ReadOptions {
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(
+            vec!["1", "4"],
+            res.queries.iter().map(|q| q.id.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["invalid contents on line 2", "invalid contents on line 3"],
+            res.skipped.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_csv_multiple_modes() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode,traffic_model
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,driving|transit|walking,best_guess
+            2,-37.820189,145.149954,-37.819681,144.952302,1537000000,transit|walking,
+            3,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking|driving,
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let options = ReadOptions {
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(
+            vec![
+                ("1:driving", Mode::Driving),
+                ("1:transit", Mode::Transit),
+                ("1:walking", Mode::Walking),
+                ("2:transit", Mode::Transit),
+                ("2:walking", Mode::Walking),
+            ],
+            res.queries
+                .iter()
+                .map(|q| (q.id.as_str(), q.mode))
+                .collect::<Vec<_>>()
+        );
+
+        // The driving query of the last row lacks a traffic model
+        assert_eq!(
+            vec![
+                "invalid contents on line 3",
+                "traffic model not supplied, this must be provided when driving is selected",
+            ],
+            res.skipped[0].causes().map(|c| c.to_string()).collect::<Vec<_>>()
+        );
+
+        // The limit counts rows rather than the queries built from them
+        let options = ReadOptions {
+            limit: Some(1),
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(
+            vec!["1:driving", "1:transit", "1:walking"],
+            res.queries.iter().map(|q| q.id.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    /// Fields that only apply to some modes are kept for those modes alone.
+    #[test]
+    fn test_csv_multiple_modes_fields() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode,traffic_model,avoidances,transit_mode
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,driving|transit|walking,best_guess,tolls|ferries,bus
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let res = read_csv(inp, &now, &ReadOptions::default()).unwrap().queries;
+        assert_eq!(3, res.len());
+        assert_eq!(Some(TrafficModel::BestGuess), res[0].traffic_model);
+        assert_eq!(
+            Some(Avoidances::new(&[Avoidance::Tolls, Avoidance::Ferries])),
+            res[0].avoidances
+        );
+        assert_eq!(None, res[0].transit_modes);
+        for query in &res[1..] {
+            assert_eq!(None, query.traffic_model);
+            assert_eq!(Some(Avoidances::new(&[Avoidance::Ferries])), query.avoidances);
+        }
+        assert_eq!(Some(TransitModes::new(&[TransitMode::Bus])), res[1].transit_modes);
+        assert_eq!(None, res[2].transit_modes);
+    }
+
+    #[test]
+    fn test_csv_live() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,arrival_time,mode,traffic_model
+            1,-37.820189,145.149954,-37.819681,144.952302,1534284000,,driving,pessimistic
+            2,-37.820189,145.149954,-37.819681,144.952302,,1534284000,driving,
+            3,-37.820189,145.149954,-37.819681,144.952302,1534284000,,walking,
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let options = ReadOptions {
+            live: true,
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(inp, &now, &options).unwrap().queries;
+        for query in &res[..2] {
+            assert_eq!("departure_time=1536991111", query.time.to_string());
+            assert_eq!(Some(TrafficModel::BestGuess), query.traffic_model);
+        }
+        assert_eq!("departure_time=1537308000", res[2].time.to_string());
+        assert_eq!(None, res[2].traffic_model);
+    }
+
+    #[test]
+    fn test_csv_limit() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            2,-37.820189,145.149954,-37.819681,144.952302,1537000000,flying
+            3,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            4,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        // Rows after the limit are not read, so the invalid row is not reached.
+        let options = ReadOptions {
+            limit: Some(1),
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(1, res.queries.len());
+
+        // Invalid rows that are skipped do not count towards the limit.
+        let options = ReadOptions {
+            limit: Some(2),
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(
+            vec!["1", "3"],
+            res.queries.iter().map(|q| q.id.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(1, res.skipped.len());
+    }
+
+    #[test]
+    fn test_json_skip_invalid() {
+        let inp = r#"[
+            {"id": "1", "origin_lat": -37.82, "origin_lon": 145.15,
+             "destination_lat": -37.82, "destination_lon": 144.95,
+             "departure_time": 1537000000, "mode": "walking"},
+            {"id": "2", "origin_lat": "north"}
+        ]"#;
+        let options = ReadOptions {
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+
+        let res = read_json(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options).unwrap();
+        assert_eq!(1, res.queries.len());
+        assert_eq!(
+            vec!["invalid contents in record 2"],
+            res.skipped.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    /// Misspelt field names are rejected rather than silently ignored.
+    #[test]
+    fn test_json_unknown_field() {
+        let inp = r#"[
+            {"id": "1", "orgin_lat": -37.82, "origin_lon": 145.15,
+             "destination_lat": -37.82, "destination_lon": 144.95,
+             "departure_time": 1537000000, "mode": "walking"}
+        ]"#;
+
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let err = read_json(inp, &now, &ReadOptions::default()).unwrap_err();
+        let causes = err.causes().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!("invalid contents in record 1", causes[0]);
+        assert!(causes[1].starts_with("unknown field `orgin_lat`"));
+    }
+
+    /// Errors report the line, field and value at fault.
+    #[test]
+    fn test_csv_invalid_coordinate_context() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            2,-37.820189,145.149954,-37.819681,200.0,1537000000,walking
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let err = read_csv(inp, &now, &ReadOptions::default()).unwrap_err();
+        assert_eq!(
+            vec![
+                "invalid contents on line 2",
+                "invalid destination_lon supplied",
+                "longitude 200 out of range, expected -180 to 180",
+            ],
+            err.causes().map(|c| c.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_csv_invalid_field_context() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            1,-37.820189,145.149954,-37.819681,144.952302,soon,walking
+            2,-37.820189,145.149954,-37.819681,144.952302,1537000000,flying
+        ");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let options = ReadOptions {
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(inp, &now, &options).unwrap();
+        assert_eq!(
+            vec![
+                "invalid contents on line 1",
+                "invalid departure_time supplied",
+                "invalid time supplied (soon), expected a UNIX timestamp, RFC 3339 time or now",
+            ],
+            res.skipped[0].causes().map(|c| c.to_string()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                "invalid contents on line 2",
+                "invalid mode supplied",
+                "unrecognised mode of transport (flying)",
+            ],
+            res.skipped[1].causes().map(|c| c.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_csv_delimiter() {
+        let inp = indoc!("
+            id;origin_lat;origin_lon;destination_lat;destination_lon;departure_time;mode
+            1;-37.820189 ;145.149954;-37.819681;144.952302;1537000000;walking
+        ").replace(';', "\t");
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let options = ReadOptions {
+            delimiter: Some(b'\t'),
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(&inp, &now, &options).unwrap().queries;
+        assert_eq!(
+            Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            res[0].origin
+        );
+
+        // Misspelled columns are still rejected
+        let inp = indoc!("
+            ids;origin_lat;origin_lon;destination_lat;destination_lon;departure_time;mode
+            1;-37.820189;145.149954;-37.819681;144.952302;1537000000;walking
+        ");
+        let options = ReadOptions {
+            delimiter: Some(b';'),
+            ..ReadOptions::default()
+        };
+        assert!(read_csv(inp, &now, &options).is_err());
+    }
+
+    /// Each file is read separately, so every file has its own header row,
+    /// and errors name the file at fault.
+    #[test]
+    fn test_read_paths() {
+        let dir = TempDir::new("telesto_test_read_paths");
+        let write = |name: &str, contents: &str| {
+            let path = dir.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let a = write("a.csv", indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            1,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+        "));
+        let b = write("b.csv", indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode
+            2,-37.820189,145.149954,-37.819681,144.952302,1537000000,walking
+            3,-37.820189,145.149954,-37.819681,144.952302,1537000000,flying
+        "));
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let options = ReadOptions {
+            skip_invalid: true,
+            ..ReadOptions::default()
+        };
+
+        let res = read_paths(&[a.clone(), b.clone()], None, &now, &options).unwrap();
+        let ids = res.queries.iter().map(|q| q.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["1", "2"], ids);
+        assert_eq!(1, res.skipped.len());
+        assert_eq!(
+            format!("invalid input file ({})", b),
+            res.skipped[0].to_string()
+        );
+
+        // A single file reports errors as before
+        let res = read_paths(&[b.clone()], None, &now, &ReadOptions::default());
+        assert_eq!("invalid contents on line 2", res.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_id_template() {
+        let template = "{origin_lat},{origin_lon}->{destination_lat},{destination_lon}@{mode}"
+            .parse::<IdTemplate>()
+            .unwrap();
+        let inp = indoc!("
+            origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode,avoidances,traffic_model
+            -37.820189,145.149954,-37.819681,144.952302,1534284000,driving,tolls,best_guess
+        ");
+
+        let options = ReadOptions {
+            id_template: Some(template),
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+
+        assert_eq!(
+            "-37.820189,145.149954->-37.819681,144.952302@driving",
+            res[0].id
+        );
+    }
+
+    /// Geometry columns list the longitude before the latitude.
+    #[test]
+    fn test_read_geometry_csv() {
+        let inp = indoc!(r#"
+            id,origin_geometry,destination_geometry,departure_time,mode
+            1,POINT(145.149954 -37.820189),"POINT (144.952302 -37.819681)",1534284000,walking
+        "#);
+
+        let options = ReadOptions {
+            geometry_format: Some(GeometryFormat::Wkt),
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+
+        assert_eq!(
+            Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            res[0].origin
+        );
+        assert_eq!(
+            Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            res[0].destination
+        );
+    }
+
+    /// Templates are rendered from the coordinates read from geometries, and
+    /// for each mode of a row with several.
+    #[test]
+    fn test_id_template_geometry_modes() {
+        let inp = indoc!("
+            origin_geometry,destination_geometry,departure_time,mode,traffic_model
+            POINT(145.149954 -37.820189),POINT(144.952302 -37.819681),1534284000,driving|walking,best_guess
+        ");
+
+        let options = ReadOptions {
+            id_template: Some("{origin_lat},{origin_lon}@{mode}".parse().unwrap()),
+            geometry_format: Some(GeometryFormat::Wkt),
+            ..ReadOptions::default()
+        };
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+
+        assert_eq!(
+            vec!["-37.820189,145.149954@driving", "-37.820189,145.149954@walking"],
+            res.iter().map(|q| q.id.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_read_geometry_csv_missing_geometry() {
+        let inp = indoc!("
+            id,origin_geometry,departure_time,mode
+            1,POINT(145.149954 -37.820189),1534284000,walking
+        ");
+
+        let options = ReadOptions {
+            geometry_format: Some(GeometryFormat::GeoJson),
+            ..ReadOptions::default()
+        };
+        assert!(read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options).is_err());
+    }
+
+    #[test]
+    fn test_read_valid_json() {
+        let inp = r#"[
+            {"id": "1", "origin_lat": -37.820189, "origin_lon": 145.149954,
+             "destination_lat": "1 Flinders St, Melbourne", "destination_lon": "",
+             "departure_time": 1534284000, "mode": "driving", "traffic_model": "best_guess"}
+        ]"#;
+
+        let options = ReadOptions::default();
+        let res = read_json(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+        assert_eq!(1, res.len());
+        assert_eq!(
+            Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            res[0].origin
+        );
+        assert_eq!(
+            Location::Address("1 Flinders St, Melbourne".to_string()),
+            res[0].destination
+        );
+        assert_eq!(
+            TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            res[0].time
+        );
+    }
+
+    #[test]
+    fn test_read_invalid_json() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let res = read_json("[{\"id\": \"1\",\n", &now, &ReadOptions::default());
+        assert_eq!("invalid JSON on line 2, column 0", res.unwrap_err().to_string());
+
+        let res = read_json(r#"{"id": "1"}"#, &now, &ReadOptions::default());
+        assert!(res.is_err());
+
+        let inp = r#"[{"id": "1", "origin_lat": -37.820189, "origin_lon": 145.149954,
+            "destination_lat": -37.819681, "destination_lon": 144.952302,
+            "departure_time": 1534284000, "mode": "flying"}]"#;
+        let res = read_json(inp, &now, &ReadOptions::default());
+        assert_eq!("invalid contents in record 1", res.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_input_format_from_path() {
+        assert_eq!(InputFormat::Json, InputFormat::from_path(&Some("in.JSON".to_string())));
+        assert_eq!(InputFormat::Csv, InputFormat::from_path(&Some("in.csv".to_string())));
+        assert_eq!(InputFormat::Csv, InputFormat::from_path(&Some("in".to_string())));
+        assert_eq!(InputFormat::Csv, InputFormat::from_path(&None));
+        assert_eq!(InputFormat::Json, InputFormat::from_path(&Some("in.json.gz".to_string())));
+        assert_eq!(InputFormat::Csv, InputFormat::from_path(&Some("in.csv.gz".to_string())));
+    }
+
+    #[test]
+    fn test_decode() {
+        let text = "id,origin_lat\n1,-37.820189\n";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(text, decode(text.as_bytes().to_vec(), false).unwrap());
+        assert_eq!(text, decode(compressed.clone(), false).unwrap());
+        assert_eq!(text, decode(compressed, true).unwrap());
+        assert!(decode(text.as_bytes().to_vec(), true).is_err());
+        assert!(decode(vec![0xff, 0xfe], false).is_err());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode,units,language
+            1,-37.820189,145.149954,-37.819681,144.952302,1534284000,walking,,
+            2,-37.820189,145.149954,-37.819681,144.952302,1534284000,walking,imperial,fr
+        ");
+        let defaults = Defaults::new(vec![
+            ("units".to_string(), "metric".to_string()),
+            ("language".to_string(), "en-AU".to_string()),
+        ]).unwrap();
+        let options = ReadOptions {
+            defaults,
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+        assert_eq!(Some(Units::Metric), res[0].units);
+        assert_eq!(Some("en-AU".to_string()), res[0].language);
+        assert_eq!(Some(Units::Imperial), res[1].units);
+        assert_eq!(Some("fr".to_string()), res[1].language);
+
+        assert!(Defaults::new(vec![("origin_lat".to_string(), "0".to_string())]).is_err());
+    }
+
+    #[test]
+    fn test_defaults_mode() {
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time,mode,traffic_model,avoidances
+            1,-37.820189,145.149954,-37.819681,144.952302,1534284000,,,
+            2,-37.820189,145.149954,-37.819681,144.952302,1534284000,walking,pessimistic,indoors
+        ");
+        let defaults = Defaults::new(vec![
+            ("mode".to_string(), "driving".to_string()),
+            ("traffic_model".to_string(), "best_guess".to_string()),
+            ("avoidances".to_string(), "tolls".to_string()),
+        ]).unwrap();
+        let options = ReadOptions {
+            defaults,
+            ..ReadOptions::default()
+        };
+
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+        assert_eq!(Mode::Driving, res[0].mode);
+        assert_eq!(Some(TrafficModel::BestGuess), res[0].traffic_model);
+        assert_eq!(Some(Avoidances::new(&[Avoidance::Tolls])), res[0].avoidances);
+        assert_eq!(Mode::Walking, res[1].mode);
+        assert_eq!(Some(TrafficModel::Pessimistic), res[1].traffic_model);
+        assert_eq!(Some(Avoidances::new(&[Avoidance::Indoors])), res[1].avoidances);
+
+        // The mode column can be left out entirely.
+        let inp = indoc!("
+            id,origin_lat,origin_lon,destination_lat,destination_lon,departure_time
+            1,-37.820189,145.149954,-37.819681,144.952302,1534284000
+        ");
+        let res = read_csv(inp, &NaiveDateTime::from_timestamp(1536991111, 0), &options)
+            .unwrap()
+            .queries;
+        assert_eq!(Mode::Driving, res[0].mode);
+    }
+
+    #[test]
+    fn test_id_template_placeholders() {
+        assert!("{mode}-{traffic_model}".parse::<IdTemplate>().is_ok());
+        assert!("route".parse::<IdTemplate>().is_ok());
+        assert!("{id}".parse::<IdTemplate>().is_err());
+        assert!("{unknown}".parse::<IdTemplate>().is_err());
+        assert!("{mode".parse::<IdTemplate>().is_err());
     }
 }
@@ -0,0 +1,97 @@
+use std::cmp;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+
+/// A source of random delays of up to a maximum number of milliseconds,
+/// used to de-correlate instances sharing a quota. Delays are reproducible
+/// when a seed is supplied.
+pub struct Jitter {
+    rng: StdRng,
+    max_ms: u64,
+}
+
+impl Jitter {
+    pub fn new(max_ms: u64, seed: Option<u64>) -> Jitter {
+        let rng = match seed {
+            Some(seed) => {
+                let mut bytes = [0; 32];
+                for (i, byte) in bytes.iter_mut().take(8).enumerate() {
+                    *byte = (seed >> (8 * i)) as u8;
+                }
+                StdRng::from_seed(bytes)
+            }
+            None => StdRng::from_entropy(),
+        };
+        Jitter { rng, max_ms }
+    }
+
+    /// Draw the next delay, uniformly distributed between zero and the
+    /// maximum inclusive.
+    pub fn delay(&mut self) -> Duration {
+        let max_ms = self.max_ms;
+        self.draw(max_ms)
+    }
+
+    /// Draw the next delay, uniformly distributed between zero and the
+    /// lesser of the given bound and the maximum inclusive.
+    pub fn delay_up_to(&mut self, bound: Duration) -> Duration {
+        let bound_ms = bound
+            .as_secs()
+            .saturating_mul(1000)
+            .saturating_add(u64::from(bound.subsec_millis()));
+        let max_ms = cmp::min(bound_ms, self.max_ms);
+        self.draw(max_ms)
+    }
+
+    /// Draw a delay between zero and `max_ms` inclusive, or just short of it
+    /// when it is the largest representable value.
+    fn draw(&mut self, max_ms: u64) -> Duration {
+        Duration::from_millis(self.rng.gen_range(0, max_ms.saturating_add(1)))
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_bounds() {
+        let mut jitter = Jitter::new(10, None);
+        for _ in 0..100 {
+            assert!(jitter.delay() <= Duration::from_millis(10));
+        }
+
+        let mut jitter = Jitter::new(0, None);
+        assert_eq!(Duration::from_millis(0), jitter.delay());
+
+        let mut jitter = Jitter::new(u64::max_value(), Some(42));
+        jitter.delay();
+        jitter.delay_up_to(Duration::from_secs(u64::max_value()));
+    }
+
+    #[test]
+    fn test_delay_up_to_bounds() {
+        let mut jitter = Jitter::new(1000, Some(42));
+        for _ in 0..100 {
+            assert!(jitter.delay_up_to(Duration::from_millis(100)) <= Duration::from_millis(100));
+            assert!(jitter.delay_up_to(Duration::from_secs(5)) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn test_seeded_delays() {
+        let mut jitter1 = Jitter::new(1000, Some(42));
+        let mut jitter2 = Jitter::new(1000, Some(42));
+        let mut jitter3 = Jitter::new(1000, Some(43));
+
+        let res1 = (0..10).map(|_| jitter1.delay()).collect::<Vec<_>>();
+        let res2 = (0..10).map(|_| jitter2.delay()).collect::<Vec<_>>();
+        let res3 = (0..10).map(|_| jitter3.delay()).collect::<Vec<_>>();
+
+        assert_eq!(res1, res2);
+        assert_ne!(res1, res3);
+    }
+}
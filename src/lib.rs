@@ -0,0 +1,54 @@
+//! Batch requests to the Google Maps Directions API.
+//!
+//! The `telesto` binary is a thin wrapper around this library: queries are
+//! read with `input`, turned into signed URLs with `TaggedUrl`, and sent with
+//! `execute_requests`, which returns a `TaggedResponse` for each one.
+
+extern crate base64;
+extern crate chrono;
+#[macro_use]
+extern crate clap;
+extern crate crypto;
+extern crate csv;
+extern crate dirs;
+#[macro_use]
+extern crate failure;
+extern crate flate2;
+extern crate futures;
+#[cfg_attr(test, macro_use)]
+extern crate indoc;
+extern crate itertools;
+#[macro_use]
+extern crate percent_encoding;
+extern crate rand;
+extern crate rayon;
+extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate toml;
+#[cfg(test)]
+extern crate xml;
+
+pub mod config;
+pub mod datum;
+pub mod geometry;
+pub mod input;
+pub mod jitter;
+pub mod output;
+pub mod polyline;
+pub mod query;
+pub mod request;
+pub mod response;
+pub mod url;
+
+pub use config::{Credentials, CredentialsBuilder};
+pub use query::Query;
+pub use request::{execute_requests, RequestOptions};
+pub use response::TaggedResponse;
+pub use url::TaggedUrl;
+
+pub type Result<T> = std::result::Result<T, failure::Error>;
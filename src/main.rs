@@ -1,41 +1,20 @@
-extern crate base64;
 extern crate chrono;
 #[macro_use]
-extern crate clap;
-extern crate crypto;
-extern crate csv;
-#[macro_use]
 extern crate failure;
-extern crate futures;
-#[cfg_attr(test, macro_use)] 
-extern crate indoc;
-extern crate itertools;
-extern crate ratelimit;
-extern crate reqwest;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-#[macro_use]
-extern crate serde_json;
-extern crate tokio_core;
+extern crate telesto;
 
+use std::path::Path;
 use std::process;
+use std::thread;
+use std::time::Duration;
 
 use chrono::prelude::Utc;
 
-mod config;
-mod input;
-mod output;
-mod query;
-mod request;
-mod response;
-mod url;
-
-type Result<T> = std::result::Result<T, failure::Error>;
+use telesto::{config, input, jitter, output, request, response, url};
+use telesto::Result;
 
 fn main() {
-    let args = config::run();
-    if let Err(err) = run(&args) {
+    if let Err(err) = config::run().and_then(|args| run(&args)) {
         eprintln!("{}", format_error(&err));
         let backtrace = err.backtrace().to_string();
         if !backtrace.trim().is_empty() {
@@ -57,25 +36,264 @@ fn format_error(err: &failure::Error) -> String {
     out
 }
 
-fn run(args: &config::Args) -> Result<()> {
-    // Load CSV input from specified path or STDIN.
-    let input_str = input::load(&args.input_path)?;
+/// Number of example ids listed for each error category in the summary.
+const ERROR_EXAMPLES: usize = 3;
+
+/// Category in the summary for invalid input rows that were skipped.
+const SKIPPED_CATEGORY: &str = "INVALID_INPUT";
+
+/// Values mixed into the seed for chunk and retry jitter, so that a seeded
+/// run draws a different sequence of delays for each use.
+const CHUNK_SEED_MASK: u64 = 0x9e37_79b9_7f4a_7c15;
+const RETRY_SEED_MASK: u64 = 0xbf58_476d_1ce4_e5b9;
+
+fn format_error_summary(groups: &[response::ErrorGroup], verbose: bool) -> String {
+    let total: usize = groups.iter().map(|g| g.failures.len()).sum();
+    let mut out = format!("{} request(s) failed:\n", total);
+    let width = groups.iter().map(|g| g.category.len()).max().unwrap_or(0);
+    for group in groups {
+        let examples = group
+            .failures
+            .iter()
+            .take(ERROR_EXAMPLES)
+            .map(|f| f.0.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let more = if group.failures.len() > ERROR_EXAMPLES {
+            ", ..."
+        } else {
+            ""
+        };
+        out.push_str(&format!(
+            "  {:width$}  {:>6}  e.g. {}{}\n",
+            group.category,
+            group.failures.len(),
+            examples,
+            more,
+            width = width
+        ));
+        if verbose {
+            for (id, message) in &group.failures {
+                match message {
+                    Some(message) => out.push_str(&format!("    {}: {}\n", id, message)),
+                    None => out.push_str(&format!("    {}\n", id)),
+                }
+            }
+        }
+    }
+    out
+}
 
-    // Build and validate queries.
+fn format_status_summary(counts: &[(String, usize)]) -> String {
+    let total: usize = counts.iter().map(|c| c.1).sum();
+    let mut out = format!("{} response(s) received:\n", total);
+    let width = counts.iter().map(|c| c.0.len()).max().unwrap_or(0);
+    for (status, count) in counts {
+        out.push_str(&format!("  {:width$}  {:>6}\n", status, count, width = width));
+    }
+    out
+}
+
+fn run(args: &config::Args) -> Result<()> {
+    // Load input from the specified paths or STDIN, then build and validate
+    // queries.
     let current_time = Utc::now().naive_utc();
-    let queries = input::read_csv(&input_str, &current_time)?;
+    let read_options = input::ReadOptions {
+        id_template: args.id_template.clone(),
+        geometry_format: args.geometry_format,
+        defaults: args.defaults.clone(),
+        skip_invalid: args.skip_invalid,
+        delimiter: args.delimiter,
+        bbox: args.bbox.clone(),
+        limit: args.limit,
+        no_shift: args.no_shift,
+        live: args.live,
+        coord_precision: Some(args.coord_precision),
+    };
+    let batch = input::read_paths(
+        &args.input_paths,
+        args.input_format,
+        &current_time,
+        &read_options,
+    )?;
+    let (mut queries, skipped) = (batch.queries, batch.skipped);
+
+    // Skip queries that completed successfully in a previous run.
+    let ids = queries.iter().map(|q| q.id.clone()).collect::<Vec<_>>();
+    let completed = match args.resume {
+        Some(ref path) => Some(response::load_completed(path)?),
+        None => None,
+    };
+    if let Some(ref completed) = completed {
+        queries.retain(|q| !completed.contains_key(&q.id));
+    }
+
+    // Convert coordinates to WGS84 if supplied in another datum.
+    for query in &mut queries {
+        query.map_coords(|c| args.datum.to_wgs84(c));
+    }
 
     // Generate request URLs.
-    let requests: Vec<_> = queries
-        .into_iter()
-        .map(|q| url::TaggedUrl::new(&q, &args.credentials).unwrap())
-        .collect();
+    let requests = url::build_urls(
+        &queries,
+        &args.credentials,
+        &args.base_url,
+        args.coord_precision,
+    )?;
+
+    // Stop short of sending requests when only the URLs are wanted.
+    if args.dry_run {
+        let output_str = output::render_urls(&requests, args.pretty)?;
+        output::export(
+            &args.output_path,
+            &output_str,
+            output::OutputFormat::Json,
+            args.verify_output,
+        )?;
+        report_errors(&skipped, Vec::new(), args.verbose);
+        return report_skipped(&skipped);
+    }
+
+    // Delay the start of requests to de-correlate from other instances.
+    if let Some(max_ms) = args.startup_jitter {
+        thread::sleep(jitter::Jitter::new(max_ms, args.seed).delay());
+    }
 
     // Collect responses.
-    let output = request::execute_requests(&requests, 50)?;
-    let output_json = serde_json::to_string(&output)?;
+    let chunk_seed = args.seed.map(|s| s ^ CHUNK_SEED_MASK);
+    let chunk_jitter = args.chunk_jitter.map(|max_ms| jitter::Jitter::new(max_ms, chunk_seed));
+    let retry_jitter = if args.retry_jitter {
+        let retry_seed = args.seed.map(|s| s ^ RETRY_SEED_MASK);
+        Some(jitter::Jitter::new(args.max_backoff, retry_seed))
+    } else {
+        None
+    };
+    let options = request::RequestOptions {
+        rate_limit: args.rate_limit,
+        concurrency: args.concurrency,
+        mode_rate_limits: args.mode_rate_limits.clone(),
+        max_inflight_bytes: args.max_inflight_bytes,
+        max_retries: args.max_retries,
+        max_backoff: Duration::from_millis(args.max_backoff),
+        record_attempts: args.record_attempts,
+        progress: args.progress,
+        timeout: args.timeout,
+        deadline: args.deadline.map(Duration::from_secs),
+        user_agent: args.user_agent.clone(),
+        headers: args.headers.clone(),
+        proxy: args.proxy.clone(),
+        dedup: args.dedup,
+        include_url: args.include_url,
+        steps: args.steps,
+        fail_fast: args.fail_fast,
+    };
+
+    // When streaming, responses are written as they arrive and there is
+    // nothing left to export afterwards.
+    if args.stream {
+        let mut writer = output::stream(&args.output_path)?;
+        request::execute_requests(
+            &requests,
+            &options,
+            chunk_jitter,
+            retry_jitter,
+            Some(&mut writer),
+        )?;
+        report_errors(&skipped, Vec::new(), args.verbose);
+        return report_skipped(&skipped);
+    }
+    let mut output =
+        request::execute_requests(&requests, &options, chunk_jitter, retry_jitter, None)?;
+    if let Some(ref completed) = completed {
+        output = response::merge_completed(&ids, completed, output);
+    }
+
+    // Summarise any skipped rows and failed requests.
+    report_errors(&skipped, response::group_errors(&output), args.verbose);
+    if args.summary {
+        eprint!("{}", format_status_summary(&response::count_statuses(&output)));
+    }
+
+    // Export results, either to a file per id or all together.
+    if let Some(ref dir) = args.split_dir {
+        output::export_split(Path::new(dir), &output, args.pretty, args.verify_output)?;
+    } else {
+        let (output_str, omitted) =
+            output::render(&output, args.output_format, args.pretty, &Utc::now())?;
+        if omitted > 0 {
+            eprintln!(
+                "{} id(s) without a valid route were omitted from the {} output",
+                omitted, args.output_format
+            );
+        }
+        output::export(
+            &args.output_path,
+            &output_str,
+            args.output_format,
+            args.verify_output,
+        )?;
+    }
+    report_skipped(&skipped)?;
 
-    // Export results.
-    output::export(&args.output_path, &output_json)?;
+    // Fail the run if asked to treat unsuccessful responses as errors.
+    let failed = response::count_failures(&output);
+    if args.fail_on_error && failed > 0 {
+        Err(RunError::FailedRequests { count: failed })?;
+    }
     Ok(())
 }
+
+/// Print a summary of failures to stderr, if there were any, with invalid
+/// input rows that were skipped listed ahead of failed requests.
+fn report_errors(
+    skipped: &[failure::Error],
+    mut groups: Vec<response::ErrorGroup>,
+    verbose: bool,
+) {
+    if !skipped.is_empty() {
+        let group = response::ErrorGroup {
+            category: SKIPPED_CATEGORY.to_string(),
+            failures: skipped
+                .iter()
+                .map(|err| (err.to_string(), format_causes(err)))
+                .collect(),
+        };
+        groups.insert(0, group);
+    }
+    if !groups.is_empty() {
+        eprint!("{}", format_error_summary(&groups, verbose));
+    }
+}
+
+/// Fail the run if any invalid input rows were skipped, so that the exit
+/// code shows that the output is incomplete.
+fn report_skipped(skipped: &[failure::Error]) -> Result<()> {
+    if skipped.is_empty() {
+        return Ok(());
+    }
+    Err(RunError::SkippedRows {
+        count: skipped.len(),
+    })?
+}
+
+/// Join the causes of an error into a single line, if it has any.
+fn format_causes(err: &failure::Error) -> Option<String> {
+    let causes = err
+        .causes()
+        .skip(1)
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>();
+    if causes.is_empty() {
+        None
+    } else {
+        Some(causes.join(": "))
+    }
+}
+
+#[derive(Debug, Fail)]
+enum RunError {
+    #[fail(display = "{} invalid input row(s) were skipped", count)]
+    SkippedRows { count: usize },
+    #[fail(display = "{} request(s) did not succeed", count)]
+    FailedRequests { count: usize },
+}
@@ -1,15 +1,602 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use chrono::{DateTime, SecondsFormat, Utc};
+use failure;
+use failure::ResultExt;
+use csv;
+use serde::Serialize;
+use serde_json;
+
+use polyline;
+use response::TaggedResponse;
+use url::TaggedUrl;
 use Result;
 
-pub fn export(path: &Option<String>, contents: &str) -> Result<()> {
-    match path {
-        Some(path) => {
-            let mut file = File::create(&path)?;
-            file.write_all(contents.as_bytes())?;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Ndjson,
+    Csv,
+    Gpx,
+}
+
+impl FromStr for OutputFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            "gpx" => Ok(OutputFormat::Gpx),
+            _ => Err(OutputError::UnknownFormat { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Gpx => "gpx",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+/// Serialise the responses in the given output format, along with the number
+/// of responses left out of it. JSON is indented when `pretty` is set,
+/// whereas NDJSON always has one response per line. Only GPX leaves out
+/// responses, namely those without a valid route.
+pub fn render(
+    responses: &[TaggedResponse],
+    format: OutputFormat,
+    pretty: bool,
+    now: &DateTime<Utc>,
+) -> Result<(String, usize)> {
+    match format {
+        OutputFormat::Json => Ok((to_json(responses, pretty)?, 0)),
+        OutputFormat::Ndjson => Ok((to_ndjson(responses)?, 0)),
+        OutputFormat::Csv => Ok((to_csv(responses)?, 0)),
+        OutputFormat::Gpx => to_gpx(responses, now),
+    }
+}
+
+/// Serialise the URLs that would be requested, along with their ids.
+pub fn render_urls(requests: &[TaggedUrl], pretty: bool) -> Result<String> {
+    let urls = requests
+        .iter()
+        .map(|r| json!({"id": r.id, "url": r.url.as_str()}))
+        .collect::<Vec<_>>();
+    to_json(&urls, pretty)
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T, pretty: bool) -> Result<String> {
+    if pretty {
+        Ok(serde_json::to_string_pretty(value)?)
+    } else {
+        Ok(serde_json::to_string(value)?)
+    }
+}
+
+fn to_ndjson(responses: &[TaggedResponse]) -> Result<String> {
+    let lines = responses
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    Ok(lines.join("\n"))
+}
+
+/// A summary of a single response, as written to CSV output.
+#[derive(Debug, PartialEq, Serialize)]
+struct CsvRow<'a> {
+    id: &'a str,
+    status: &'a str,
+    distance_meters: Option<i64>,
+    duration_seconds: Option<i64>,
+}
+
+/// Build a CSV document with one row per response. The total distance and
+/// duration of the first route are left blank unless the status is OK.
+fn to_csv(responses: &[TaggedResponse]) -> Result<String> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    for response in responses {
+        wtr.serialize(CsvRow {
+            id: &response.id,
+            status: response.response["status"].as_str().unwrap_or(""),
+            distance_meters: response.distance_meters,
+            duration_seconds: response.duration_seconds,
+        })?;
+    }
+    let bytes = wtr.into_inner()?;
+    Ok(String::from_utf8(bytes)?.trim_right().to_string())
+}
+
+pub fn export(
+    path: &Option<String>,
+    contents: &str,
+    format: OutputFormat,
+    verify: bool,
+) -> Result<()> {
+    if verify {
+        match format {
+            OutputFormat::Json => verify_json(contents)?,
+            OutputFormat::Ndjson => verify_ndjson(contents)?,
+            OutputFormat::Csv | OutputFormat::Gpx => (),
         }
+    }
+
+    match path {
+        Some(path) => write_atomic(Path::new(path), contents)?,
         None => println!("{}", contents),
     };
     Ok(())
 }
+
+/// A destination that responses are written to as soon as they arrive.
+pub trait ResponseSink {
+    fn write(&mut self, response: &TaggedResponse) -> Result<()>;
+}
+
+/// Writes each response as a line of NDJSON, flushing after every line so
+/// that completed responses are kept even if the run is interrupted.
+pub struct NdjsonWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(writer: W) -> NdjsonWriter<W> {
+        NdjsonWriter { writer }
+    }
+}
+
+impl<W: Write> ResponseSink for NdjsonWriter<W> {
+    fn write(&mut self, response: &TaggedResponse) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, response)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Open a streaming NDJSON writer to the specified path or STDOUT.
+pub fn stream(path: &Option<String>) -> Result<NdjsonWriter<Box<Write>>> {
+    let writer: Box<Write> = match path {
+        Some(path) => Box::new(File::create(path).context(OutputError::Write {
+            path: path.clone(),
+        })?),
+        None => Box::new(io::stdout()),
+    };
+    Ok(NdjsonWriter::new(writer))
+}
+
+/// Write each response as JSON to its own file in the given directory, named
+/// by its id. Characters in ids that are unsafe in file names are replaced,
+/// and ids that would share a file name are rejected rather than overwritten.
+pub fn export_split(
+    dir: &Path,
+    responses: &[TaggedResponse],
+    pretty: bool,
+    verify: bool,
+) -> Result<()> {
+    let mut file_names = HashSet::new();
+    for response in responses {
+        let file_name = format!("{}.json", sanitise_file_stem(&response.id));
+        if !file_names.insert(file_name.clone()) {
+            Err(OutputError::DuplicateFile { file_name })?
+        }
+    }
+
+    fs::create_dir_all(dir).context(OutputError::Write {
+        path: dir.display().to_string(),
+    })?;
+    for response in responses {
+        let path = dir.join(format!("{}.json", sanitise_file_stem(&response.id)));
+        let contents = to_json(response, pretty)?;
+        if verify {
+            verify_json(&contents)?;
+        }
+        write_atomic(&path, &contents)?;
+    }
+    Ok(())
+}
+
+/// Replace characters that could escape a directory or are not portable in
+/// file names with underscores.
+fn sanitise_file_stem(id: &str) -> String {
+    let stem = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        }).collect::<String>();
+    match stem.as_str() {
+        "" => "_".to_string(),
+        "." | ".." => stem.replace('.', "_"),
+        _ => stem,
+    }
+}
+
+/// Build a GPX document with a track for the first route of each response,
+/// named by id. Returns the document along with the number of responses
+/// omitted because they did not contain a valid route.
+fn to_gpx(responses: &[TaggedResponse], now: &DateTime<Utc>) -> Result<(String, usize)> {
+    let mut gpx = String::new();
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str(
+        "<gpx version=\"1.1\" creator=\"Telesto\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    gpx.push_str(&format!(
+        "  <metadata><time>{}</time></metadata>\n",
+        now.to_rfc3339_opts(SecondsFormat::Secs, true)
+    ));
+
+    let mut omitted = 0;
+    for response in responses {
+        let encoded = response.response["routes"][0]["overview_polyline"]["points"].as_str();
+        let points = match encoded.map(polyline::decode) {
+            Some(Ok(points)) => points,
+            Some(Err(_)) | None => {
+                omitted += 1;
+                continue;
+            }
+        };
+
+        gpx.push_str("  <trk>\n");
+        gpx.push_str(&format!("    <name>{}</name>\n", escape_xml(&response.id)));
+        gpx.push_str("    <trkseg>\n");
+        for (lat, lon) in points {
+            gpx.push_str(&format!(
+                "      <trkpt lat=\"{:.5}\" lon=\"{:.5}\"/>\n",
+                lat, lon
+            ));
+        }
+        gpx.push_str("    </trkseg>\n");
+        gpx.push_str("  </trk>\n");
+    }
+    gpx.push_str("</gpx>\n");
+
+    Ok((gpx, omitted))
+}
+
+fn escape_xml(inp: &str) -> String {
+    inp.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Check that the assembled output parses as a JSON document.
+fn verify_json(contents: &str) -> Result<()> {
+    serde_json::from_str::<serde_json::Value>(contents).context(OutputError::InvalidJson)?;
+    Ok(())
+}
+
+/// Check that each line of the assembled output parses as a JSON document.
+fn verify_ndjson(contents: &str) -> Result<()> {
+    for line in contents.lines() {
+        verify_json(line)?;
+    }
+    Ok(())
+}
+
+/// Write the contents to a temporary file alongside the destination, then
+/// rename it into place so that a partially written file is never visible.
+fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let tmp_path = temp_path(path);
+    let res = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        }).and_then(|_| fs::rename(&tmp_path, path));
+
+    if let Err(err) = res {
+        let _ = fs::remove_file(&tmp_path);
+        Err(err).context(OutputError::Write {
+            path: path.display().to_string(),
+        })?
+    }
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", file_name))
+}
+
+#[derive(Debug, Fail)]
+enum OutputError {
+    #[fail(display = "unrecognised output format ({})", unk)]
+    UnknownFormat { unk: String },
+    #[fail(display = "output is not valid JSON")]
+    InvalidJson,
+    #[fail(display = "could not write output file ({})", path)]
+    Write { path: String },
+    #[fail(display = "more than one id would be written to the same file ({})", file_name)]
+    DuplicateFile { file_name: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use query::Mode;
+    use std::env;
+    use std::process;
+    use xml::reader::{EventReader, XmlEvent};
+
+    /// A scratch directory unique to this process, removed when dropped so
+    /// that it is cleaned up even if the test fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("{}_{}", name, process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_ndjson_writer() {
+        let mut writer = NdjsonWriter::new(Vec::new());
+        writer
+            .write(&TaggedResponse::new("2", r#"{"status": "OK"}"#))
+            .unwrap();
+        writer
+            .write(&TaggedResponse::new("1", r#"{"status": "NOT_FOUND"}"#))
+            .unwrap();
+
+        let contents = String::from_utf8(writer.writer).unwrap();
+        assert_eq!(
+            "{\"id\":\"2\",\"response\":{\"status\":\"OK\"}}\n\
+             {\"id\":\"1\",\"response\":{\"status\":\"NOT_FOUND\"}}\n",
+            contents
+        );
+        assert!(verify_ndjson(&contents).is_ok());
+    }
+
+    #[test]
+    fn test_verify_json() {
+        assert!(verify_json(r#"[{"id": "1", "response": {}}]"#).is_ok());
+        assert!(verify_json(r#"[{"id": "1", "response": {"#).is_err());
+    }
+
+    #[test]
+    fn test_verify_ndjson() {
+        assert!(verify_ndjson("{\"id\": \"1\"}\n{\"id\": \"2\"}").is_ok());
+        assert!(verify_ndjson("[{\"id\": \"1\"},\n{\"id\": \"2\"}]").is_err());
+    }
+
+    #[test]
+    fn test_render_ndjson() {
+        let responses = vec![
+            TaggedResponse::new("1", r#"{"status": "OK"}"#),
+            TaggedResponse::new("2", r#"{"status": "NOT_FOUND"}"#),
+        ];
+        let now = Utc.timestamp(1536991111, 0);
+
+        // Pretty printing is ignored so that each response stays on one line
+        let res = render(&responses, OutputFormat::Ndjson, true, &now).unwrap().0;
+        assert_eq!(
+            vec![
+                r#"{"id":"1","response":{"status":"OK"}}"#,
+                r#"{"id":"2","response":{"status":"NOT_FOUND"}}"#,
+            ],
+            res.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_render_csv() {
+        let responses = vec![
+            TaggedResponse::new(
+                "1",
+                r#"{"status": "OK", "routes": [{"legs": [
+                    {"distance": {"value": 1200}, "duration": {"value": 300}},
+                    {"distance": {"value": 800}, "duration": {"value": 120}}
+                ]}]}"#,
+            ),
+            TaggedResponse::new("2", r#"{"status": "ZERO_RESULTS", "routes": []}"#),
+            TaggedResponse::new("3", "<html>"),
+        ];
+        let now = Utc.timestamp(1536991111, 0);
+
+        let res = render(&responses, OutputFormat::Csv, false, &now).unwrap().0;
+        assert_eq!(
+            indoc!(
+                "
+                id,status,distance_meters,duration_seconds
+                1,OK,2000,420
+                2,ZERO_RESULTS,,
+                3,MALFORMED_JSON,,"
+            ),
+            res
+        );
+    }
+
+    #[test]
+    fn test_temp_path() {
+        assert_eq!(
+            Path::new("dir/.out.json.tmp"),
+            temp_path(Path::new("dir/out.json")).as_path()
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = TempDir::new("telesto_test_write_atomic");
+        let path = dir.0.join("out.json");
+        write_atomic(&path, "[]").unwrap();
+
+        assert_eq!("[]", fs::read_to_string(&path).unwrap());
+        assert!(!temp_path(&path).exists());
+    }
+
+    /// A failed write should leave any existing output in place.
+    #[test]
+    fn test_write_atomic_failure() {
+        let dir = TempDir::new("telesto_test_write_atomic_failure");
+        let path = dir.0.join("out.json");
+        fs::write(&path, "[1]").unwrap();
+        fs::create_dir_all(temp_path(&path)).unwrap();
+
+        let res = export(
+            &Some(path.display().to_string()),
+            "[]",
+            OutputFormat::Json,
+            false,
+        );
+        assert!(res.is_err());
+        assert_eq!("[1]", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sanitise_file_stem() {
+        assert_eq!("route-1_a.b", sanitise_file_stem("route-1_a.b"));
+        assert_eq!(".._etc_passwd", sanitise_file_stem("../etc/passwd"));
+        assert_eq!("a_b_c", sanitise_file_stem("a\\b:c"));
+        assert_eq!("__", sanitise_file_stem(".."));
+        assert_eq!("_", sanitise_file_stem(""));
+    }
+
+    #[test]
+    fn test_export_split() {
+        let base = TempDir::new("telesto_test_export_split");
+        let dir = base.0.join("split");
+        let responses = vec![
+            TaggedResponse::new("1", r#"{"status": "OK"}"#),
+            TaggedResponse::new("a/b", r#"{"status": "NOT_FOUND"}"#),
+        ];
+
+        export_split(&dir, &responses, false, true).unwrap();
+        assert_eq!(
+            r#"{"id":"1","response":{"status":"OK"}}"#,
+            fs::read_to_string(dir.join("1.json")).unwrap()
+        );
+        assert_eq!(
+            r#"{"id":"a/b","response":{"status":"NOT_FOUND"}}"#,
+            fs::read_to_string(dir.join("a_b.json")).unwrap()
+        );
+
+        // Files are replaced whole, without leaving temporary files behind
+        export_split(&dir, &responses, false, false).unwrap();
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Ids that would overwrite each other are rejected
+        let responses = vec![
+            TaggedResponse::new("a/b", r#"{"status": "OK"}"#),
+            TaggedResponse::new("a_b", r#"{"status": "OK"}"#),
+        ];
+        assert!(export_split(&dir, &responses, false, false).is_err());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_render_urls() {
+        let requests = vec![TaggedUrl {
+            id: "1".to_string(),
+            mode: Mode::Walking,
+            url: "https://maps.googleapis.com/maps/api/directions/json?mode=walking"
+                .parse()
+                .unwrap(),
+        }];
+
+        assert_eq!(
+            r#"[{"id":"1","url":"https://maps.googleapis.com/maps/api/directions/json?mode=walking"}]"#,
+            render_urls(&requests, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_pretty() {
+        let responses = vec![TaggedResponse::new("1", r#"{"status": "OK"}"#)];
+        let now = Utc.timestamp(1536991111, 0);
+
+        let compact = render(&responses, OutputFormat::Json, false, &now).unwrap().0;
+        let pretty = render(&responses, OutputFormat::Json, true, &now).unwrap().0;
+        assert_eq!(r#"[{"id":"1","response":{"status":"OK"}}]"#, compact);
+        assert!(pretty.lines().count() > 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!(OutputFormat::Json, "json".parse().unwrap());
+        assert_eq!(OutputFormat::Ndjson, "ndjson".parse().unwrap());
+        assert_eq!(OutputFormat::Csv, "csv".parse().unwrap());
+        assert_eq!(OutputFormat::Gpx, "gpx".parse().unwrap());
+        assert!("kml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_to_gpx() {
+        let responses = vec![
+            TaggedResponse::new(
+                "a&b",
+                r#"{"status": "OK", "routes": [{"overview_polyline": {"points": "_p~iF~ps|U_ulLnnqC"}}]}"#,
+            ),
+            TaggedResponse::new("2", r#"{"status": "ZERO_RESULTS", "routes": []}"#),
+            TaggedResponse::new(
+                "3",
+                r#"{"status": "OK", "routes": [{"overview_polyline": {"points": "_p~iF~ps|U_ulL"}}]}"#,
+            ),
+        ];
+        let now = Utc.timestamp(1536991111, 0);
+
+        let (gpx, omitted) = to_gpx(&responses, &now).unwrap();
+        assert_eq!(2, omitted);
+
+        // The document must be well-formed XML
+        let mut names = Vec::new();
+        let mut points = 0;
+        let mut in_name = false;
+        for event in EventReader::from_str(&gpx) {
+            match event.unwrap() {
+                XmlEvent::StartElement { name, .. } => {
+                    in_name = name.local_name == "name";
+                    if name.local_name == "trkpt" {
+                        points += 1;
+                    }
+                }
+                XmlEvent::Characters(text) => {
+                    if in_name {
+                        names.push(text);
+                    }
+                }
+                _ => in_name = false,
+            }
+        }
+
+        assert_eq!(vec!["a&b".to_string()], names);
+        assert_eq!(2, points);
+        assert!(gpx.contains("<time>2018-09-15T05:58:31Z</time>"));
+    }
+}
@@ -0,0 +1,99 @@
+use Result;
+
+/// Decode a string in Google's encoded polyline format into a list of
+/// latitude/longitude pairs.
+pub fn decode(encoded: &str) -> Result<Vec<(f64, f64)>> {
+    let mut points = Vec::new();
+    let mut bytes = encoded.bytes();
+    let mut lat: i64 = 0;
+    let mut lon: i64 = 0;
+
+    loop {
+        let delta_lat = match decode_value(&mut bytes, encoded)? {
+            Some(value) => value,
+            None => break,
+        };
+        let delta_lon = decode_value(&mut bytes, encoded)?.ok_or_else(|| {
+            PolylineError::Truncated {
+                inv: encoded.to_string(),
+            }
+        })?;
+
+        lat += delta_lat;
+        lon += delta_lon;
+        points.push((lat as f64 / 1e5, lon as f64 / 1e5));
+    }
+
+    Ok(points)
+}
+
+/// Decode a single signed value, returning None if the input is exhausted
+/// before the value begins.
+fn decode_value<I>(bytes: &mut I, encoded: &str) -> Result<Option<i64>>
+where
+    I: Iterator<Item = u8>,
+{
+    let mut result: i64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = match bytes.next() {
+            Some(byte) => byte,
+            None if shift == 0 => return Ok(None),
+            None => Err(PolylineError::Truncated {
+                inv: encoded.to_string(),
+            })?,
+        };
+        if byte < 63 || byte > 126 || shift > 60 {
+            Err(PolylineError::InvalidCharacter {
+                inv: encoded.to_string(),
+            })?
+        }
+
+        let chunk = i64::from(byte - 63);
+        result |= (chunk & 0x1f) << shift;
+        shift += 5;
+        if chunk < 0x20 {
+            break;
+        }
+    }
+
+    let value = if result & 1 == 1 {
+        !(result >> 1)
+    } else {
+        result >> 1
+    };
+    Ok(Some(value))
+}
+
+#[derive(Debug, Fail)]
+enum PolylineError {
+    #[fail(display = "encoded polyline ends part way through a value ({})", inv)]
+    Truncated { inv: String },
+    #[fail(display = "encoded polyline contains an invalid character ({})", inv)]
+    InvalidCharacter { inv: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+
+    /// Example taken from the Encoded Polyline Algorithm Format documentation.
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            vec![(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)],
+            decode("_p~iF~ps|U_ulLnnqC_mqNvxq`@").unwrap()
+        );
+        assert_eq!(Vec::<(f64, f64)>::new(), decode("").unwrap());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode("_p~iF").is_err());
+        assert!(decode("_p~i").is_err());
+        assert!(decode(" ").is_err());
+        assert!(decode("\x7f").is_err());
+    }
+}
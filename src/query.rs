@@ -1,20 +1,38 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 use std::fmt;
 use std::str::FromStr;
 
-use chrono::{Duration, NaiveDateTime};
+use chrono::{DateTime, Duration, NaiveDateTime};
 use failure;
 use failure::ResultExt;
 use itertools;
+use percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
 
 use input;
+use polyline;
 use Result;
 
 const LAT_BOUNDS: [f64; 2] = [-90.0, 90.0];
 const LON_BOUNDS: [f64; 2] = [-180.0, 180.0];
 const WEEK_IN_SECONDS: i64 = 60 * 60 * 24 * 7;
+const PLACE_ID_PREFIX: &str = "place_id:";
+const ENCODED_PREFIX: &str = "enc:";
+const VIA_PREFIX: &str = "via:";
 
-#[derive(Debug, PartialEq)]
+/// Decimal places given for coordinates unless a precision is supplied.
+pub const DEFAULT_COORD_PRECISION: usize = 6;
+
+define_encode_set! {
+    /// Characters that must be escaped within a single query parameter value.
+    pub PARAMETER_ENCODE_SET = [QUERY_ENCODE_SET] | {'%', '&', '+', '=', '|'}
+}
+
+/// Percent-encode a free-form string for use as a query parameter value.
+fn encode_parameter(inp: &str) -> String {
+    utf8_percent_encode(inp, PARAMETER_ENCODE_SET).to_string()
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Coord {
     pub lat: f64,
     pub lon: f64,
@@ -26,15 +44,167 @@ impl Coord {
             Err(ParseError::InvalidLatitude { inv: lat })?
         };
         if lon < LON_BOUNDS[0] || lon > LON_BOUNDS[1] {
-            Err(ParseError::InvalidLongitude { inv: lat })?
+            Err(ParseError::InvalidLongitude { inv: lon })?
         };
         Ok(Coord { lat, lon })
     }
 }
 
+/// The precision of the formatter, e.g. `{:.4}`, sets the number of decimal
+/// places, which otherwise defaults to `DEFAULT_COORD_PRECISION`.
 impl fmt::Display for Coord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:.6},{:.6}", self.lat, self.lon)
+        let precision = f.precision().unwrap_or(DEFAULT_COORD_PRECISION);
+        write!(f, "{:.*},{:.*}", precision, self.lat, precision, self.lon)
+    }
+}
+
+/// A region that coordinates are expected to fall within, to catch data
+/// errors such as a flipped sign. A box whose minimum longitude is greater
+/// than its maximum crosses the antimeridian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min: Coord,
+    pub max: Coord,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, coord: &Coord) -> bool {
+        let lat = self.min.lat <= coord.lat && coord.lat <= self.max.lat;
+        let lon = if self.min.lon <= self.max.lon {
+            self.min.lon <= coord.lon && coord.lon <= self.max.lon
+        } else {
+            self.min.lon <= coord.lon || coord.lon <= self.max.lon
+        };
+        lat && lon
+    }
+
+    /// Check that every coordinate in a query falls within the box.
+    /// Addresses and place IDs are not checked.
+    pub fn check(&self, query: &Query) -> Result<()> {
+        let mut locations = vec![("origin", &query.origin), ("destination", &query.destination)];
+        if let Some(waypoints) = &query.waypoints {
+            locations.extend(waypoints.points.iter().map(|p| ("waypoints", &p.location)));
+        }
+        for (field, location) in locations {
+            if let Location::Coords(coord) = location {
+                if !self.contains(coord) {
+                    Err(ParseError::OutsideBoundingBox {
+                        coord: coord.to_string(),
+                    }).context(ParseError::InvalidField { field })?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = failure::Error;
+
+    /// Parse a box from 'minLat,minLon,maxLat,maxLon'.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || ParseError::InvalidBoundingBox { inv: s.to_string() };
+        let values = s
+            .split(',')
+            .map(|v| to_f64(v.trim()))
+            .collect::<Result<Vec<_>>>()
+            .context(invalid())?;
+        match values.as_slice() {
+            [min_lat, min_lon, max_lat, max_lon] if min_lat <= max_lat => Ok(BoundingBox {
+                min: Coord::new(*min_lat, *min_lon).context(invalid())?,
+                max: Coord::new(*max_lat, *max_lon).context(invalid())?,
+            }),
+            _ => Err(invalid())?,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Location {
+    Coords(Coord),
+    Address(String),
+    PlaceId(String),
+    /// A path in Google's encoded polyline format, only accepted as a
+    /// waypoint and passed to the API as supplied.
+    Encoded(String),
+}
+
+impl Location {
+    /// Build a location from a pair of latitude/longitude fields. If the
+    /// longitude is blank and the latitude is not a number, the latitude
+    /// field is instead taken to hold a place ID (when prefixed with
+    /// 'place_id:') or an address. Errors name the field at fault, from the
+    /// names of the latitude and longitude fields in `fields`.
+    pub fn from_fields(
+        lat: &str,
+        lon: &str,
+        fields: (&'static str, &'static str),
+    ) -> Result<Location> {
+        let (lat_field, lon_field) = fields;
+        if lon.is_empty() && lat.starts_with(PLACE_ID_PREFIX) {
+            return Ok(Location::from_place_id(lat)
+                .context(ParseError::InvalidField { field: lat_field })?);
+        }
+        if lon.is_empty() && !lat.is_empty() && lat.parse::<f64>().is_err() {
+            return Ok(Location::Address(lat.to_string()));
+        }
+
+        let lat = to_f64(lat).context(ParseError::InvalidField { field: lat_field })?;
+        let lon = to_f64(lon).context(ParseError::InvalidField { field: lon_field })?;
+        let field = if lat < LAT_BOUNDS[0] || lat > LAT_BOUNDS[1] {
+            lat_field
+        } else {
+            lon_field
+        };
+        Ok(Location::Coords(
+            Coord::new(lat, lon).context(ParseError::InvalidField { field })?,
+        ))
+    }
+
+    /// Build a location from a 'place_id:' prefixed place ID.
+    fn from_place_id(inp: &str) -> Result<Location> {
+        let place_id = inp[PLACE_ID_PREFIX.len()..].trim();
+        if place_id.is_empty() {
+            Err(ParseError::InvalidPlaceId {
+                inv: inp.to_string(),
+            })?
+        }
+        Ok(Location::PlaceId(place_id.to_string()))
+    }
+
+    /// Build a location from an 'enc:' prefixed and ':' terminated encoded
+    /// polyline, which must decode to at least one point.
+    fn from_encoded(inp: &str) -> Result<Location> {
+        let invalid = || ParseError::InvalidWaypoint {
+            inv: inp.to_string(),
+        };
+        if inp.len() <= ENCODED_PREFIX.len() || !inp.ends_with(':') {
+            Err(invalid())?
+        }
+        let encoded = &inp[ENCODED_PREFIX.len()..inp.len() - 1];
+        match polyline::decode(encoded) {
+            Ok(ref points) if !points.is_empty() => Ok(Location::Encoded(encoded.to_string())),
+            _ => Err(invalid())?,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Coords(coord) => {
+                let precision = f.precision().unwrap_or(DEFAULT_COORD_PRECISION);
+                write!(f, "{:.*}", precision, coord)
+            }
+            Location::Address(address) => write!(f, "{}", encode_parameter(address)),
+            Location::PlaceId(place_id) => {
+                write!(f, "{}{}", PLACE_ID_PREFIX, encode_parameter(place_id))
+            }
+            Location::Encoded(encoded) => {
+                write!(f, "{}{}:", ENCODED_PREFIX, encode_parameter(encoded))
+            }
+        }
     }
 }
 
@@ -44,7 +214,9 @@ pub struct DepartureTime(NaiveDateTime);
 impl DepartureTime {
     pub fn new(timestamp: i64) -> Result<DepartureTime> {
         let timestamp = NaiveDateTime::from_timestamp_opt(timestamp, 0)
-            .ok_or_else(|| ParseError::InvalidTime { inv: timestamp })?;
+            .ok_or_else(|| ParseError::InvalidTime {
+                inv: timestamp.to_string(),
+            })?;
         Ok(DepartureTime(timestamp))
     }
 
@@ -52,20 +224,40 @@ impl DepartureTime {
     /// timestamp, then shift the departure time to the closest future
     /// time that falls on the same day of week and time of day.
     pub fn shift(&self, now: &NaiveDateTime) -> DepartureTime {
-        let timestamp = if self.0 < *now {
-            let delta = now.signed_duration_since(self.0).num_seconds();
-            let remainder = delta % WEEK_IN_SECONDS;
-            let offset = Duration::weeks(match remainder {
-                0 => delta / WEEK_IN_SECONDS,
-                _ => delta / WEEK_IN_SECONDS + 1,
-            });
-            self.0 + offset
-        } else {
-            self.0
-        };
+        DepartureTime(shift_timestamp(&self.0, now))
+    }
+}
 
-        DepartureTime(timestamp)
+/// Shift a timestamp occurring before 'now' forward by whole weeks, so
+/// that it becomes the closest future time on the same day of week and
+/// time of day. Timestamps that are not in the past are left unchanged.
+fn shift_timestamp(timestamp: &NaiveDateTime, now: &NaiveDateTime) -> NaiveDateTime {
+    if timestamp < now {
+        let delta = now.signed_duration_since(*timestamp).num_seconds();
+        let remainder = delta % WEEK_IN_SECONDS;
+        let offset = Duration::weeks(match remainder {
+            0 => delta / WEEK_IN_SECONDS,
+            _ => delta / WEEK_IN_SECONDS + 1,
+        });
+        *timestamp + offset
+    } else {
+        *timestamp
+    }
+}
+
+/// Parse a time given as a UNIX timestamp, an RFC 3339 date and time such as
+/// "2018-08-15T08:00:00+10:00", or the literal "now", into a UNIX timestamp.
+fn parse_timestamp(inp: &str, now: &NaiveDateTime) -> Result<i64> {
+    if inp == "now" {
+        return Ok(now.timestamp());
+    }
+    if let Ok(timestamp) = to_i64(inp) {
+        return Ok(timestamp);
     }
+    let time = DateTime::parse_from_rfc3339(inp).map_err(|_| ParseError::InvalidTime {
+        inv: inp.to_string(),
+    })?;
+    Ok(time.timestamp())
 }
 
 impl fmt::Display for DepartureTime {
@@ -75,6 +267,98 @@ impl fmt::Display for DepartureTime {
 }
 
 #[derive(Debug, PartialEq)]
+pub struct ArrivalTime(NaiveDateTime);
+
+impl ArrivalTime {
+    pub fn new(timestamp: i64) -> Result<ArrivalTime> {
+        let timestamp = NaiveDateTime::from_timestamp_opt(timestamp, 0)
+            .ok_or_else(|| ParseError::InvalidTime {
+                inv: timestamp.to_string(),
+            })?;
+        Ok(ArrivalTime(timestamp))
+    }
+
+    /// If the current arrival time occurs before the specified 'now'
+    /// timestamp, then shift the arrival time to the closest future
+    /// time that falls on the same day of week and time of day.
+    pub fn shift(&self, now: &NaiveDateTime) -> ArrivalTime {
+        ArrivalTime(shift_timestamp(&self.0, now))
+    }
+}
+
+impl fmt::Display for ArrivalTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.timestamp())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TimeConstraint {
+    Departure(DepartureTime),
+    Arrival(ArrivalTime),
+}
+
+impl TimeConstraint {
+    /// Build a time constraint from optional departure and arrival time
+    /// fields, exactly one of which must be supplied. See `parse_timestamp` for
+    /// the accepted forms.
+    pub fn from_fields(
+        departure_time: Option<&str>,
+        arrival_time: Option<&str>,
+        now: &NaiveDateTime,
+    ) -> Result<TimeConstraint> {
+        match (departure_time, arrival_time) {
+            (Some(d), None) => Ok(TimeConstraint::Departure(
+                parse_timestamp(d, now)
+                    .and_then(DepartureTime::new)
+                    .context(ParseError::InvalidField {
+                        field: "departure_time",
+                    })?,
+            )),
+            (None, Some(a)) => Ok(TimeConstraint::Arrival(
+                parse_timestamp(a, now)
+                    .and_then(ArrivalTime::new)
+                    .context(ParseError::InvalidField {
+                        field: "arrival_time",
+                    })?,
+            )),
+            (Some(_), Some(_)) => Err(ParseError::ConflictingTimes.into()),
+            (None, None) => Err(ParseError::MissingTime.into()),
+        }
+    }
+
+    /// Check that a traffic model can be used with this time. The Directions
+    /// API only honours a traffic model for a departure time that is not in
+    /// the past.
+    fn check_traffic_model(&self, now: &NaiveDateTime) -> Result<()> {
+        match self {
+            TimeConstraint::Departure(t) if t.0 < *now => Err(ParseError::PastDepartureTime {
+                time: t.to_string(),
+            })?,
+            TimeConstraint::Departure(_) => Ok(()),
+            TimeConstraint::Arrival(_) => Err(ParseError::TrafficModelWithArrival)?,
+        }
+    }
+
+    /// Shift whichever timestamp is present, see `DepartureTime::shift`.
+    pub fn shift(&self, now: &NaiveDateTime) -> TimeConstraint {
+        match self {
+            TimeConstraint::Departure(t) => TimeConstraint::Departure(t.shift(now)),
+            TimeConstraint::Arrival(t) => TimeConstraint::Arrival(t.shift(now)),
+        }
+    }
+}
+
+impl fmt::Display for TimeConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeConstraint::Departure(t) => write!(f, "departure_time={}", t),
+            TimeConstraint::Arrival(t) => write!(f, "arrival_time={}", t),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub enum Mode {
     Bicycling,
     Driving,
@@ -108,7 +392,8 @@ impl fmt::Display for Mode {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+/// Variants are declared in the canonical order that they are emitted in.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Avoidance {
     Tolls,
     Highways,
@@ -130,6 +415,19 @@ impl FromStr for Avoidance {
     }
 }
 
+impl Avoidance {
+    /// Whether the API takes this avoidance into account for the given mode.
+    /// Tolls and highways only apply to driving, and indoor steps only to
+    /// walking.
+    pub fn supports(&self, mode: Mode) -> bool {
+        match self {
+            Avoidance::Tolls | Avoidance::Highways => mode == Mode::Driving,
+            Avoidance::Indoors => mode == Mode::Walking,
+            Avoidance::Ferries => true,
+        }
+    }
+}
+
 impl fmt::Display for Avoidance {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let out = match self {
@@ -142,8 +440,10 @@ impl fmt::Display for Avoidance {
     }
 }
 
+/// A set of avoidances. An ordered set is used so that the parameter, and
+/// therefore the signature of premium URLs, is the same from run to run.
 #[derive(Debug, PartialEq)]
-pub struct Avoidances(HashSet<Avoidance>);
+pub struct Avoidances(BTreeSet<Avoidance>);
 
 impl Avoidances {
     pub fn new(inp: &[Avoidance]) -> Avoidances {
@@ -203,6 +503,249 @@ impl fmt::Display for TrafficModel {
     }
 }
 
+/// Variants are declared in the canonical order that they are emitted in.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TransitMode {
+    Bus,
+    Subway,
+    Train,
+    Tram,
+    Rail,
+}
+
+impl FromStr for TransitMode {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bus" => Ok(TransitMode::Bus),
+            "subway" => Ok(TransitMode::Subway),
+            "train" => Ok(TransitMode::Train),
+            "tram" => Ok(TransitMode::Tram),
+            "rail" => Ok(TransitMode::Rail),
+            _ => Err(ParseError::UnknownTransitMode { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for TransitMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            TransitMode::Bus => "bus",
+            TransitMode::Subway => "subway",
+            TransitMode::Train => "train",
+            TransitMode::Tram => "tram",
+            TransitMode::Rail => "rail",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+/// A set of transit modes, ordered for the same reason as `Avoidances`.
+#[derive(Debug, PartialEq)]
+pub struct TransitModes(BTreeSet<TransitMode>);
+
+impl TransitModes {
+    pub fn new(inp: &[TransitMode]) -> TransitModes {
+        TransitModes(inp.iter().cloned().collect())
+    }
+}
+
+impl FromStr for TransitModes {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Ok(TransitModes::new(&Vec::new()));
+        }
+
+        let iter = s.split('|').map(|m| m.parse::<TransitMode>());
+        let modes = itertools::process_results(iter, |iter| iter.collect::<Vec<_>>())?;
+        Ok(TransitModes::new(&modes))
+    }
+}
+
+impl fmt::Display for TransitModes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = itertools::join(self.0.iter(), "|");
+        write!(f, "{}", out)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransitRoutingPreference {
+    LessWalking,
+    FewerTransfers,
+}
+
+impl FromStr for TransitRoutingPreference {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "less_walking" => Ok(TransitRoutingPreference::LessWalking),
+            "fewer_transfers" => Ok(TransitRoutingPreference::FewerTransfers),
+            _ => Err(ParseError::UnknownTransitRoutingPreference { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for TransitRoutingPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            TransitRoutingPreference::LessWalking => "less_walking",
+            TransitRoutingPreference::FewerTransfers => "fewer_transfers",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+impl FromStr for Units {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            _ => Err(ParseError::UnknownUnits { unk: s.to_string() }.into()),
+        }
+    }
+}
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let out = match self {
+            Units::Metric => "metric",
+            Units::Imperial => "imperial",
+        };
+        write!(f, "{}", out)
+    }
+}
+
+/// A point that a route passes through, stopping there unless it is a via
+/// point.
+#[derive(Debug, PartialEq)]
+pub struct Waypoint {
+    pub via: bool,
+    pub location: Location,
+}
+
+impl FromStr for Waypoint {
+    type Err = failure::Error;
+
+    /// Parse a 'lat,lon' pair, a 'place_id:' prefixed place ID or an 'enc:'
+    /// prefixed encoded polyline, optionally preceded by 'via:'.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (via, s) = if s.starts_with(VIA_PREFIX) {
+            (true, s[VIA_PREFIX.len()..].trim())
+        } else {
+            (false, s)
+        };
+
+        let location = if s.starts_with(PLACE_ID_PREFIX) {
+            Location::from_place_id(s)?
+        } else if s.starts_with(ENCODED_PREFIX) {
+            Location::from_encoded(s)?
+        } else {
+            let mut parts = s.split(',');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(lat), Some(lon), None) => Location::Coords(Coord::new(
+                    to_f64(lat.trim())?,
+                    to_f64(lon.trim())?,
+                )?),
+                _ => Err(ParseError::InvalidWaypoint { inv: s.to_string() })?,
+            }
+        };
+        Ok(Waypoint { via, location })
+    }
+}
+
+/// The precision of the formatter sets the number of decimal places given for
+/// coordinates, as for `Coord`.
+impl fmt::Display for Waypoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let precision = f.precision().unwrap_or(DEFAULT_COORD_PRECISION);
+        let prefix = if self.via { VIA_PREFIX } else { "" };
+        write!(f, "{}{:.*}", prefix, precision, self.location)
+    }
+}
+
+const OPTIMIZE_PREFIX: &str = "optimize:true|";
+
+#[derive(Debug, PartialEq)]
+pub struct Waypoints {
+    pub optimize: bool,
+    pub points: Vec<Waypoint>,
+}
+
+impl FromStr for Waypoints {
+    type Err = failure::Error;
+
+    /// Parse a pipe separated list of waypoints, optionally preceded by
+    /// 'optimize:true|' to allow the API to reorder them. Via points cannot
+    /// be reordered, so are not accepted alongside 'optimize:true'.
+    fn from_str(s: &str) -> Result<Self> {
+        let (optimize, s) = if s.starts_with(OPTIMIZE_PREFIX) {
+            (true, &s[OPTIMIZE_PREFIX.len()..])
+        } else {
+            (false, s)
+        };
+
+        let iter = split_waypoints(s).into_iter().map(|w| w.parse::<Waypoint>());
+        let points = itertools::process_results(iter, |iter| iter.collect::<Vec<_>>())?;
+        if optimize && points.iter().any(|p| p.via) {
+            Err(ParseError::OptimizedViaWaypoints)?
+        }
+        Ok(Waypoints { optimize, points })
+    }
+}
+
+/// Split a list of waypoints on the pipes between them. An encoded polyline
+/// may contain pipes of its own, so it runs on to the colon that ends it.
+fn split_waypoints(s: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    let mut open = false;
+    for piece in s.split('|') {
+        match parts.last_mut() {
+            Some(last) if open => {
+                last.push('|');
+                last.push_str(piece);
+            }
+            _ => parts.push(piece.to_string()),
+        }
+        let part = parts[parts.len() - 1].trim();
+        let part = if part.starts_with(VIA_PREFIX) {
+            part[VIA_PREFIX.len()..].trim()
+        } else {
+            part
+        };
+        open = part.starts_with(ENCODED_PREFIX) && !part[ENCODED_PREFIX.len()..].contains(':');
+    }
+    parts
+}
+
+impl fmt::Display for Waypoints {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let precision = f.precision().unwrap_or(DEFAULT_COORD_PRECISION);
+        let points = itertools::join(
+            self.points.iter().map(|p| format!("{:.*}", precision, p)),
+            "|",
+        );
+        if self.optimize {
+            write!(f, "{}{}", OPTIMIZE_PREFIX, points)
+        } else {
+            write!(f, "{}", points)
+        }
+    }
+}
+
 fn to_i64(inp: &str) -> Result<i64> {
     Ok(inp.parse::<i64>().context(ParseError::InvalidInt {
         inv: inp.to_string(),
@@ -215,82 +758,264 @@ fn to_f64(inp: &str) -> Result<f64> {
     })?)
 }
 
+fn to_bool(inp: &str) -> Result<bool> {
+    Ok(inp.parse::<bool>().context(ParseError::InvalidBool {
+        inv: inp.to_string(),
+    })?)
+}
+
+fn non_empty(inp: &Option<String>) -> Option<&str> {
+    match inp {
+        Some(s) if !s.is_empty() => Some(s.as_str()),
+        _ => None,
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Query {
     pub id: String,
-    pub origin: Coord,
-    pub destination: Coord,
-    pub departure_time: DepartureTime,
+    pub origin: Location,
+    pub destination: Location,
+    pub time: TimeConstraint,
     pub mode: Mode,
     pub avoidances: Option<Avoidances>,
     pub traffic_model: Option<TrafficModel>,
+    pub waypoints: Option<Waypoints>,
+    pub units: Option<Units>,
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub transit_modes: Option<TransitModes>,
+    pub transit_routing_preference: Option<TransitRoutingPreference>,
+    pub alternatives: bool,
 }
 
 impl Query {
-    pub fn from_csv_record(inp: input::CsvRecord, now: &NaiveDateTime) -> Result<Query> {
-        let origin_lat = to_f64(&inp.origin_lat)?;
-        let origin_lon = to_f64(&inp.origin_lon)?;
-        let destination_lat = to_f64(&inp.destination_lat)?;
-        let destination_lon = to_f64(&inp.destination_lon)?;
-        let departure_time = to_i64(&inp.departure_time)?;
-
-        let id = inp.id;
-        let origin = Coord::new(origin_lat, origin_lon)?;
-        let destination = Coord::new(destination_lat, destination_lon)?;
-        let departure_time = DepartureTime::new(departure_time)?.shift(now);
-        let mode = inp.mode.parse::<Mode>()?;
+    /// Build a query from an input record. Past times are shifted into the
+    /// future relative to 'now' unless `shift` is false, and the origin and
+    /// destination coordinates are compared to `precision` decimal places.
+    pub fn from_record(
+        inp: input::Record,
+        now: &NaiveDateTime,
+        shift: bool,
+        precision: usize,
+    ) -> Result<Query> {
+        let id = non_empty(&inp.id)
+            .ok_or(ParseError::MissingId)?
+            .to_string();
+        let origin = Location::from_fields(
+            &inp.origin_lat,
+            &inp.origin_lon,
+            ("origin_lat", "origin_lon"),
+        )?;
+        let destination = Location::from_fields(
+            &inp.destination_lat,
+            &inp.destination_lon,
+            ("destination_lat", "destination_lon"),
+        )?;
+        let time = TimeConstraint::from_fields(
+            non_empty(&inp.departure_time),
+            non_empty(&inp.arrival_time),
+            now,
+        )?;
+        let time = if shift { time.shift(now) } else { time };
+        let mode = inp
+            .mode
+            .parse::<Mode>()
+            .context(ParseError::InvalidField { field: "mode" })?;
         let avoidances = match &inp.avoidances {
-            Some(a) => Some(a.parse::<Avoidances>()?),
+            Some(a) => Some(
+                a.parse::<Avoidances>()
+                    .context(ParseError::InvalidField {
+                        field: "avoidances",
+                    })?,
+            ),
             None => None,
         };
         let traffic_model = match &inp.traffic_model {
-            Some(t) => Some(t.parse::<TrafficModel>()?),
+            Some(t) => Some(
+                t.parse::<TrafficModel>()
+                    .context(ParseError::InvalidField {
+                        field: "traffic_model",
+                    })?,
+            ),
+            None => None,
+        };
+        let waypoints = match non_empty(&inp.waypoints) {
+            Some(w) => Some(
+                w.parse::<Waypoints>()
+                    .context(ParseError::InvalidField { field: "waypoints" })?,
+            ),
+            None => None,
+        };
+        let units = match &inp.units {
+            Some(u) => Some(
+                u.parse::<Units>()
+                    .context(ParseError::InvalidField { field: "units" })?,
+            ),
+            None => None,
+        };
+        let language = non_empty(&inp.language).map(str::to_string);
+        let region = non_empty(&inp.region).map(str::to_string);
+        let transit_modes = match &inp.transit_mode {
+            Some(m) => Some(
+                m.parse::<TransitModes>()
+                    .context(ParseError::InvalidField {
+                        field: "transit_mode",
+                    })?,
+            ),
+            None => None,
+        };
+        let transit_routing_preference = match &inp.transit_routing_preference {
+            Some(p) => Some(
+                p.parse::<TransitRoutingPreference>()
+                    .context(ParseError::InvalidField {
+                        field: "transit_routing_preference",
+                    })?,
+            ),
             None => None,
         };
+        let alternatives = match non_empty(&inp.alternatives) {
+            Some(a) => to_bool(a).context(ParseError::InvalidField {
+                field: "alternatives",
+            })?,
+            None => false,
+        };
+
+        if mode != Mode::Transit
+            && (transit_modes.is_some() || transit_routing_preference.is_some())
+        {
+            Err(ParseError::TransitOptionsWithoutTransit)?
+        };
+
+        if let Some(avoidances) = &avoidances {
+            if let Some(avoidance) = avoidances.0.iter().find(|a| !a.supports(mode)) {
+                Err(ParseError::IncompatibleAvoidance {
+                    mode,
+                    avoidance: avoidance.clone(),
+                })?
+            }
+        };
+
+        // Coordinates are compared as they are sent, to the given number of
+        // decimal places
+        if let (Location::Coords(o), Location::Coords(d)) = (&origin, &destination) {
+            let sent = format!("{:.*}", precision, o);
+            if sent == format!("{:.*}", precision, d) {
+                Err(ParseError::SameOriginDestination { coord: sent })?
+            }
+        }
 
-        if mode == Mode::Driving && traffic_model.is_none() {
-            Err(ParseError::MissingTrafficModel)?
+        match (&time, &traffic_model) {
+            // Unshifted past departure times are left for the API to reject.
+            (TimeConstraint::Departure(_), Some(_)) if !shift => (),
+            (_, Some(_)) => time.check_traffic_model(now)?,
+            (TimeConstraint::Departure(_), None) if mode == Mode::Driving => {
+                Err(ParseError::MissingTrafficModel)?
+            }
+            _ => (),
         };
 
         Ok(Query {
             id,
             origin,
             destination,
-            departure_time,
+            time,
             mode,
             avoidances,
             traffic_model,
+            waypoints,
+            units,
+            language,
+            region,
+            transit_modes,
+            transit_routing_preference,
+            alternatives,
         })
     }
 }
 
-impl fmt::Display for Query {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut query = String::new();
+impl Query {
+    /// Apply a function to every coordinate in the query, such as a datum
+    /// transformation. Addresses are left untouched.
+    pub fn map_coords<F>(&mut self, f: F)
+    where
+        F: Fn(&Coord) -> Coord,
+    {
+        for location in vec![&mut self.origin, &mut self.destination] {
+            if let Location::Coords(coord) = location {
+                *coord = f(coord);
+            }
+        }
+        if let Some(waypoints) = &mut self.waypoints {
+            for point in &mut waypoints.points {
+                if let Location::Coords(coord) = &mut point.location {
+                    *coord = f(coord);
+                }
+            }
+        }
+    }
+}
 
+impl Query {
+    /// The query parameters, in the canonical order that they are emitted
+    /// in, with coordinates given to `precision` decimal places. Parameters
+    /// with several values are emitted in a canonical order too, so equal
+    /// queries always produce identical query strings, and therefore the same
+    /// signatures.
+    fn parameters(&self, precision: usize) -> Vec<(&'static str, String)> {
         // Required parameters
-        query.push_str(&format!("origin={}", self.origin.to_string()));
-        query.push_str("&");
-        query.push_str(&format!("destination={}", self.destination.to_string()));
-        query.push_str("&");
-        query.push_str(&format!(
-            "departure_time={}",
-            self.departure_time.to_string()
-        ));
-        query.push_str("&");
-        query.push_str(&format!("mode={}", self.mode.to_string()));
+        let mut parameters = vec![
+            ("origin", format!("{:.*}", precision, self.origin)),
+            ("destination", format!("{:.*}", precision, self.destination)),
+            match &self.time {
+                TimeConstraint::Departure(t) => ("departure_time", t.to_string()),
+                TimeConstraint::Arrival(t) => ("arrival_time", t.to_string()),
+            },
+            ("mode", self.mode.to_string()),
+        ];
 
         // Optional parameters
         if let Some(avoidances) = &self.avoidances {
-            query.push_str("&");
-            query.push_str(&format!("avoid={}", avoidances.to_string()));
+            parameters.push(("avoid", avoidances.to_string()));
         };
         if let Some(traffic_model) = &self.traffic_model {
-            query.push_str("&");
-            query.push_str(&format!("traffic_model={}", traffic_model.to_string()));
+            parameters.push(("traffic_model", traffic_model.to_string()));
         };
+        if let Some(waypoints) = &self.waypoints {
+            parameters.push(("waypoints", format!("{:.*}", precision, waypoints)));
+        };
+        if let Some(units) = &self.units {
+            parameters.push(("units", units.to_string()));
+        };
+        if let Some(language) = &self.language {
+            parameters.push(("language", encode_parameter(language)));
+        };
+        if let Some(region) = &self.region {
+            parameters.push(("region", encode_parameter(region)));
+        };
+        if let Some(transit_modes) = &self.transit_modes {
+            parameters.push(("transit_mode", transit_modes.to_string()));
+        };
+        if let Some(preference) = &self.transit_routing_preference {
+            parameters.push(("transit_routing_preference", preference.to_string()));
+        };
+        if self.alternatives {
+            parameters.push(("alternatives", "true".to_string()));
+        };
+        parameters
+    }
+}
 
-        write!(f, "{}", query)
+/// The precision of the formatter sets the number of decimal places given for
+/// coordinates, as for `Coord`.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let precision = f.precision().unwrap_or(DEFAULT_COORD_PRECISION);
+        let parameters = self
+            .parameters(precision)
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value));
+        write!(f, "{}", itertools::join(parameters, "&"))
     }
 }
 
@@ -300,40 +1025,123 @@ enum ParseError {
     InvalidInt { inv: String },
     #[fail(display = "float expected, found {} instead", inv)]
     InvalidFloat { inv: String },
-    #[fail(display = "invalid latitude coordinate supplied ({})", inv)]
+    #[fail(display = "boolean expected, found {} instead", inv)]
+    InvalidBool { inv: String },
+    #[fail(display = "latitude {} out of range, expected -90 to 90", inv)]
     InvalidLatitude { inv: f64 },
-    #[fail(display = "invalid longitude coordinate supplied ({})", inv)]
+    #[fail(display = "longitude {} out of range, expected -180 to 180", inv)]
     InvalidLongitude { inv: f64 },
-    #[fail(display = "invalid UNIX timestamp supplied ({})", inv)]
-    InvalidTime { inv: i64 },
+    #[fail(display = "coordinate ({}) is outside the bounding box", coord)]
+    OutsideBoundingBox { coord: String },
+    #[fail(
+        display = "invalid bounding box supplied ({}), expected minLat,minLon,maxLat,maxLon",
+        inv
+    )]
+    InvalidBoundingBox { inv: String },
+    #[fail(display = "invalid {} supplied", field)]
+    InvalidField { field: &'static str },
+    #[fail(
+        display = "invalid time supplied ({}), expected a UNIX timestamp, RFC 3339 time or now",
+        inv
+    )]
+    InvalidTime { inv: String },
     #[fail(display = "unrecognised mode of transport ({})", unk)]
     UnknownMode { unk: String },
     #[fail(display = "unrecognised avoidance type ({})", unk)]
     UnknownAvoidance { unk: String },
+    #[fail(display = "avoidance ({}) cannot be used with mode ({})", avoidance, mode)]
+    IncompatibleAvoidance { mode: Mode, avoidance: Avoidance },
     #[fail(display = "unrecognised traffic model ({})", unk)]
     UnknownTrafficModel { unk: String },
     #[fail(display = "traffic model not supplied, this must be provided when driving is selected")]
     MissingTrafficModel,
+    #[fail(display = "unrecognised transit mode ({})", unk)]
+    UnknownTransitMode { unk: String },
+    #[fail(display = "unrecognised transit routing preference ({})", unk)]
+    UnknownTransitRoutingPreference { unk: String },
+    #[fail(display = "transit options supplied, these can only be used when transit is selected")]
+    TransitOptionsWithoutTransit,
+    #[fail(display = "origin and destination are the same ({})", coord)]
+    SameOriginDestination { coord: String },
+    #[fail(display = "unrecognised units ({})", unk)]
+    UnknownUnits { unk: String },
+    #[fail(display = "invalid place ID supplied ({})", inv)]
+    InvalidPlaceId { inv: String },
+    #[fail(
+        display = "invalid waypoint supplied ({}), expected lat,lon, place_id:ID or enc:POLYLINE:, optionally prefixed with via:",
+        inv
+    )]
+    InvalidWaypoint { inv: String },
+    #[fail(display = "via waypoints cannot be combined with optimize:true")]
+    OptimizedViaWaypoints,
+    #[fail(display = "no id supplied")]
+    MissingId,
+    #[fail(display = "neither a departure time nor an arrival time was supplied")]
+    MissingTime,
+    #[fail(display = "both a departure time and an arrival time were supplied, only one may be provided")]
+    ConflictingTimes,
+    #[fail(display = "traffic model supplied with an arrival time, this can only be used with a departure time")]
+    TrafficModelWithArrival,
+    #[fail(display = "departure time ({}) is in the past, a traffic model needs a future departure time", time)]
+    PastDepartureTime { time: String },
 }
 
 #[cfg(test)]
 #[cfg_attr(tarpaulin, skip)]
 mod tests {
     use super::*;
-    use input::CsvRecord;
+    use input::Record;
 
-    #[test]
-    fn test_parse_i64() {
-        assert_eq!(1, to_i64("1").unwrap());
-        assert!(to_i64("a").is_err());
+    /// Build a query from a record, shifting past times and comparing
+    /// coordinates at the default precision.
+    fn from_record(inp: Record, now: &NaiveDateTime) -> Result<Query> {
+        Query::from_record(inp, now, true, DEFAULT_COORD_PRECISION)
     }
 
-    #[test]
+    /// A valid record for a walking trip, to be adjusted by each test.
+    fn record() -> Record {
+        Record {
+            id: Option::Some("1".to_string()),
+            origin_lat: "-37.820189".to_string(),
+            origin_lon: "145.149954".to_string(),
+            destination_lat: "-37.819681".to_string(),
+            destination_lon: "144.952302".to_string(),
+            origin_geometry: Option::None,
+            destination_geometry: Option::None,
+            departure_time: Option::Some("1534284000".to_string()),
+            arrival_time: Option::None,
+            mode: "walking".to_string(),
+            avoidances: Option::None,
+            traffic_model: Option::None,
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_mode: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: Option::None,
+        }
+    }
+
+    #[test]
+    fn test_parse_i64() {
+        assert_eq!(1, to_i64("1").unwrap());
+        assert!(to_i64("a").is_err());
+    }
+
+    #[test]
     fn test_parse_f64() {
         assert_eq!(1.0, to_f64("1.0").unwrap());
         assert!(to_f64("a").is_err());
     }
 
+    #[test]
+    fn test_parse_bool() {
+        assert_eq!(true, to_bool("true").unwrap());
+        assert_eq!(false, to_bool("false").unwrap());
+        assert!(to_bool("yes").is_err());
+    }
+
     #[test]
     fn test_construct_coord() {
         assert!(Coord::new(90.0, 180.0).is_ok());
@@ -342,12 +1150,138 @@ mod tests {
         assert!(Coord::new(91.0, 181.0).is_err());
     }
 
+    #[test]
+    fn test_coord_error_reports_value() {
+        assert_eq!(
+            "latitude 91 out of range, expected -90 to 90",
+            Coord::new(91.0, 145.0).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "longitude 200 out of range, expected -180 to 180",
+            Coord::new(-37.0, 200.0).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_bounding_box() {
+        assert_eq!(
+            BoundingBox {
+                min: Coord::new(-39.2, 140.9).unwrap(),
+                max: Coord::new(-33.9, 150.0).unwrap(),
+            },
+            "-39.2, 140.9, -33.9, 150".parse().unwrap()
+        );
+        assert!("-39.2,140.9,-33.9".parse::<BoundingBox>().is_err());
+        assert!("-33.9,140.9,-39.2,150".parse::<BoundingBox>().is_err());
+        assert!("-39.2,140.9,-33.9,190".parse::<BoundingBox>().is_err());
+        assert!("a,b,c,d".parse::<BoundingBox>().is_err());
+    }
+
+    #[test]
+    fn test_bounding_box_contains() {
+        let bbox = "-39.2,140.9,-33.9,150".parse::<BoundingBox>().unwrap();
+        assert!(bbox.contains(&Coord::new(-37.820189, 145.149954).unwrap()));
+        assert!(bbox.contains(&Coord::new(-39.2, 140.9).unwrap()));
+        assert!(!bbox.contains(&Coord::new(37.820189, 145.149954).unwrap()));
+        assert!(!bbox.contains(&Coord::new(-37.820189, -145.149954).unwrap()));
+
+        // Boxes may cross the antimeridian
+        let bbox = "-50,170,-30,-175".parse::<BoundingBox>().unwrap();
+        assert!(bbox.contains(&Coord::new(-40.0, 175.0).unwrap()));
+        assert!(bbox.contains(&Coord::new(-40.0, -178.0).unwrap()));
+        assert!(!bbox.contains(&Coord::new(-40.0, 0.0).unwrap()));
+    }
+
+    #[test]
+    fn test_bounding_box_check() {
+        let bbox = "-39.2,140.9,-33.9,150".parse::<BoundingBox>().unwrap();
+        let mut query = Query {
+            id: "1".to_string(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Address("Melbourne".to_string()),
+            time: TimeConstraint::Departure(DepartureTime::new(1534284000).unwrap()),
+            mode: Mode::Walking,
+            avoidances: Option::None,
+            traffic_model: Option::None,
+            waypoints: Option::Some("-37.8,145.0|37.9,145.1".parse().unwrap()),
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
+        };
+
+        let err = bbox.check(&query).unwrap_err();
+        let causes = err.causes().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "invalid waypoints supplied",
+                "coordinate (37.900000,145.100000) is outside the bounding box",
+            ],
+            causes
+        );
+
+        query.waypoints = Option::None;
+        assert!(bbox.check(&query).is_ok());
+    }
+
     #[test]
     fn test_display_coord() {
         assert_eq!(
             "-37.820189,145.149954",
             Coord::new(-37.820189, 145.149954).unwrap().to_string(),
-        )
+        );
+        assert_eq!(
+            "-37.8202,145.1500",
+            format!("{:.4}", Coord::new(-37.820189, 145.149954).unwrap()),
+        );
+        assert_eq!(
+            "-38,145",
+            format!("{:.0}", Coord::new(-37.820189, 145.149954).unwrap()),
+        );
+    }
+
+    #[test]
+    fn test_location_from_fields() {
+        let from_fields = |lat, lon| Location::from_fields(lat, lon, ("origin_lat", "origin_lon"));
+        assert_eq!(
+            Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            from_fields("-37.820189", "145.149954").unwrap()
+        );
+        assert_eq!(
+            Location::Address("1 Flinders St, Melbourne".to_string()),
+            from_fields("1 Flinders St, Melbourne", "").unwrap()
+        );
+        assert_eq!(
+            Location::PlaceId("ChIJgf0RD69C1moR4OeMIXVWBAU".to_string()),
+            from_fields("place_id:ChIJgf0RD69C1moR4OeMIXVWBAU", "").unwrap()
+        );
+
+        // Errors name the field at fault
+        let field = |lat, lon| from_fields(lat, lon).unwrap_err().to_string();
+        assert_eq!("invalid origin_lat supplied", field("place_id:", ""));
+        assert_eq!("invalid origin_lat supplied", field("", ""));
+        assert_eq!("invalid origin_lon supplied", field("-37.820189", ""));
+        assert_eq!("invalid origin_lat supplied", field("a", "145.149954"));
+        assert_eq!("invalid origin_lat supplied", field("91.0", "145.149954"));
+        assert_eq!("invalid origin_lon supplied", field("-37.820189", "181.0"));
+    }
+
+    #[test]
+    fn test_display_location() {
+        assert_eq!(
+            "-37.820189,145.149954",
+            Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()).to_string()
+        );
+        assert_eq!(
+            "1%20Flinders%20St,%20Melbourne%20%26%20Co",
+            Location::Address("1 Flinders St, Melbourne & Co".to_string()).to_string()
+        );
+        assert_eq!(
+            "place_id:ChIJgf0RD69C1moR4OeMIXVWBAU",
+            Location::PlaceId("ChIJgf0RD69C1moR4OeMIXVWBAU".to_string()).to_string()
+        );
     }
 
     #[test]
@@ -383,6 +1317,165 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_shift_timestamp() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        // Past timestamps move forward by whole weeks
+        assert_eq!(
+            NaiveDateTime::from_timestamp(1537308000, 0),
+            shift_timestamp(&NaiveDateTime::from_timestamp(1534284000, 0), &now)
+        );
+
+        // A timestamp exactly a whole number of weeks in the past lands on now
+        assert_eq!(
+            now,
+            shift_timestamp(
+                &NaiveDateTime::from_timestamp(1536991111 - WEEK_IN_SECONDS * 2, 0),
+                &now
+            )
+        );
+
+        // Future timestamps are untouched
+        assert_eq!(
+            NaiveDateTime::from_timestamp(1565820000, 0),
+            shift_timestamp(&NaiveDateTime::from_timestamp(1565820000, 0), &now)
+        );
+    }
+
+    #[test]
+    fn test_csv_record_without_shift() {
+        let record = |departure_time: &str| Record {
+            departure_time: Option::Some(departure_time.to_string()),
+            mode: "driving".to_string(),
+            traffic_model: Option::Some("best_guess".to_string()),
+            ..record()
+        };
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        // Past timestamps are kept as supplied
+        assert_eq!(
+            TimeConstraint::Departure(DepartureTime::new(1534284000).unwrap()),
+            Query::from_record(record("1534284000"), &now, false, DEFAULT_COORD_PRECISION)
+                .unwrap()
+                .time
+        );
+        assert_eq!(
+            TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            Query::from_record(record("1534284000"), &now, true, DEFAULT_COORD_PRECISION)
+                .unwrap()
+                .time
+        );
+    }
+
+    #[test]
+    fn test_construct_arrival_time() {
+        assert_eq!(
+            NaiveDateTime::from_timestamp(1534284000, 0),
+            ArrivalTime::new(1534284000).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_display_arrival_time() {
+        assert_eq!("1534284000", ArrivalTime::new(1534284000).unwrap().to_string())
+    }
+
+    #[test]
+    fn test_update_arrival_time() {
+        assert_eq!(
+            ArrivalTime::new(1537308000).unwrap(),
+            ArrivalTime::new(1537308000)
+                .unwrap()
+                .shift(&NaiveDateTime::from_timestamp(1536991111, 0))
+        );
+
+        assert_eq!(
+            ArrivalTime::new(1565820000).unwrap(),
+            ArrivalTime::new(1565820000)
+                .unwrap()
+                .shift(&NaiveDateTime::from_timestamp(1536991111, 0))
+        );
+    }
+
+    #[test]
+    fn test_time_constraint_from_fields() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        assert_eq!(
+            TimeConstraint::Departure(DepartureTime::new(1534284000).unwrap()),
+            TimeConstraint::from_fields(Some("1534284000"), None, &now).unwrap()
+        );
+        assert_eq!(
+            TimeConstraint::Arrival(ArrivalTime::new(1534284000).unwrap()),
+            TimeConstraint::from_fields(None, Some("1534284000"), &now).unwrap()
+        );
+        assert!(
+            TimeConstraint::from_fields(Some("1534284000"), Some("1534284000"), &now).is_err()
+        );
+        assert!(TimeConstraint::from_fields(None, None, &now).is_err());
+        assert!(TimeConstraint::from_fields(None, Some("soon"), &now).is_err());
+    }
+
+    #[test]
+    fn test_time_constraint_check_traffic_model() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+        let departure = TimeConstraint::from_fields(Some("1534284000"), None, &now).unwrap();
+
+        assert_eq!(
+            "departure time (1534284000) is in the past, a traffic model needs a future departure time",
+            departure.check_traffic_model(&now).unwrap_err().to_string()
+        );
+        assert!(departure.shift(&now).check_traffic_model(&now).is_ok());
+        assert!(TimeConstraint::from_fields(Some("now"), None, &now)
+            .unwrap()
+            .check_traffic_model(&now)
+            .is_ok());
+        assert!(TimeConstraint::from_fields(None, Some("1537308000"), &now)
+            .unwrap()
+            .check_traffic_model(&now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        assert_eq!(1534284000, parse_timestamp("1534284000", &now).unwrap());
+        assert_eq!(1534284000, parse_timestamp("2018-08-14T22:00:00Z", &now).unwrap());
+        assert_eq!(1534284000, parse_timestamp("2018-08-15T08:00:00+10:00", &now).unwrap());
+        assert_eq!(1536991111, parse_timestamp("now", &now).unwrap());
+        assert!(parse_timestamp("2018-08-15 08:00", &now).is_err());
+        assert!(parse_timestamp("soon", &now).is_err());
+    }
+
+    #[test]
+    fn test_time_constraint_from_now() {
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        // The current time is not shifted
+        assert_eq!(
+            "departure_time=1536991111",
+            TimeConstraint::from_fields(Some("now"), None, &now)
+                .unwrap()
+                .shift(&now)
+                .to_string()
+        );
+        assert!(TimeConstraint::from_fields(Some("99999999999999999"), None, &now).is_err());
+    }
+
+    #[test]
+    fn test_display_time_constraint() {
+        assert_eq!(
+            "departure_time=1534284000",
+            TimeConstraint::Departure(DepartureTime::new(1534284000).unwrap()).to_string()
+        );
+        assert_eq!(
+            "arrival_time=1534284000",
+            TimeConstraint::Arrival(ArrivalTime::new(1534284000).unwrap()).to_string()
+        );
+    }
+
     #[test]
     fn test_parse_mode() {
         assert_eq!(Mode::Bicycling, "bicycling".parse().unwrap());
@@ -448,6 +1541,13 @@ mod tests {
         assert_eq!(inp, inp.to_string().parse().unwrap());
     }
 
+    #[test]
+    fn test_display_avoidances_canonical_order() {
+        let inp = "indoors|ferries|tolls|highways".parse::<Avoidances>().unwrap();
+
+        assert_eq!("tolls|highways|ferries|indoors", inp.to_string());
+    }
+
     #[test]
     fn test_parse_traffic_model() {
         assert_eq!(TrafficModel::BestGuess, "best_guess".parse().unwrap());
@@ -463,51 +1563,345 @@ mod tests {
         assert_eq!("pessimistic", TrafficModel::Pessimistic.to_string());
     }
 
+    #[test]
+    fn test_parse_transit_mode() {
+        assert_eq!(TransitMode::Bus, "bus".parse().unwrap());
+        assert_eq!(TransitMode::Subway, "subway".parse().unwrap());
+        assert_eq!(TransitMode::Train, "train".parse().unwrap());
+        assert_eq!(TransitMode::Tram, "tram".parse().unwrap());
+        assert_eq!(TransitMode::Rail, "rail".parse().unwrap());
+        assert!("ferry".parse::<TransitMode>().is_err());
+    }
+
+    #[test]
+    fn test_parse_transit_modes() {
+        let res = TransitModes::new(&vec![TransitMode::Bus, TransitMode::Tram]);
+
+        assert_eq!(res, "bus|tram".parse().unwrap());
+        assert_eq!(res, res.to_string().parse().unwrap());
+        assert!("bus|ferry".parse::<TransitModes>().is_err());
+        assert_eq!("bus|tram", "tram|bus".parse::<TransitModes>().unwrap().to_string());
+    }
+
+    #[test]
+    fn test_parse_transit_routing_preference() {
+        assert_eq!(
+            TransitRoutingPreference::LessWalking,
+            "less_walking".parse().unwrap()
+        );
+        assert_eq!(
+            TransitRoutingPreference::FewerTransfers,
+            "fewer_transfers".parse().unwrap()
+        );
+        assert!("more_walking".parse::<TransitRoutingPreference>().is_err());
+        assert_eq!(
+            "fewer_transfers",
+            TransitRoutingPreference::FewerTransfers.to_string()
+        );
+    }
+
+    #[test]
+    fn test_transit_options_without_transit() {
+        let inp = Record {
+            transit_mode: Option::Some("bus".to_string()),
+            ..record()
+        };
+
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(Units::Metric, "metric".parse().unwrap());
+        assert_eq!(Units::Imperial, "imperial".parse().unwrap());
+        assert!("furlongs".parse::<Units>().is_err());
+    }
+
+    #[test]
+    fn test_display_units() {
+        assert_eq!("metric", Units::Metric.to_string());
+        assert_eq!("imperial", Units::Imperial.to_string());
+    }
+
+    #[test]
+    fn test_parse_waypoints() {
+        let stop = |location| Waypoint {
+            via: false,
+            location,
+        };
+        let res1 = Waypoints {
+            optimize: false,
+            points: vec![
+                stop(Location::Coords(Coord::new(-37.820189, 145.149954).unwrap())),
+                stop(Location::Coords(Coord::new(-37.819681, 144.952302).unwrap())),
+            ],
+        };
+        let res2 = Waypoints {
+            optimize: true,
+            points: vec![stop(Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()))],
+        };
+        let res3 = Waypoints {
+            optimize: false,
+            points: vec![
+                stop(Location::PlaceId("ChIJgf0RD69C1moR4OeMIXVWBAU".to_string())),
+                stop(Location::Coords(Coord::new(-37.819681, 144.952302).unwrap())),
+            ],
+        };
+
+        assert_eq!(
+            res1,
+            "-37.820189,145.149954|-37.819681,144.952302".parse().unwrap()
+        );
+        assert_eq!(res2, "optimize:true|-37.820189,145.149954".parse().unwrap());
+        assert_eq!(
+            res3,
+            "place_id:ChIJgf0RD69C1moR4OeMIXVWBAU|-37.819681,144.952302"
+                .parse()
+                .unwrap()
+        );
+        assert!("place_id:|-37.819681,144.952302".parse::<Waypoints>().is_err());
+        assert!("-37.820189".parse::<Waypoints>().is_err());
+        assert!("-37.820189,145.149954,1.0".parse::<Waypoints>().is_err());
+        assert!("91.0,145.149954".parse::<Waypoints>().is_err());
+    }
+
+    #[test]
+    fn test_parse_encoded_and_via_waypoints() {
+        // The encoded polyline contains a pipe of its own
+        let res = "via:-37.820189,145.149954|enc:_p~iF~ps|U_ulLnnqC_mqNvxq`@:|via:enc:_p~iF~ps|U:"
+            .parse::<Waypoints>()
+            .unwrap();
+        assert_eq!(
+            Waypoints {
+                optimize: false,
+                points: vec![
+                    Waypoint {
+                        via: true,
+                        location: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+                    },
+                    Waypoint {
+                        via: false,
+                        location: Location::Encoded("_p~iF~ps|U_ulLnnqC_mqNvxq`@".to_string()),
+                    },
+                    Waypoint {
+                        via: true,
+                        location: Location::Encoded("_p~iF~ps|U".to_string()),
+                    },
+                ],
+            },
+            res
+        );
+        assert_eq!(
+            "via:-37.820189,145.149954|enc:_p~iF~ps%7CU_ulLnnqC_mqNvxq`@:|via:enc:_p~iF~ps%7CU:",
+            res.to_string()
+        );
+
+        assert!("enc::".parse::<Waypoints>().is_err());
+        assert!("enc:_p~iF~ps|U".parse::<Waypoints>().is_err());
+        assert!("enc:_p~iF:".parse::<Waypoints>().is_err());
+        assert!("via:".parse::<Waypoints>().is_err());
+        assert_eq!(
+            "invalid waypoint supplied (enc::), expected lat,lon, place_id:ID or enc:POLYLINE:, \
+             optionally prefixed with via:",
+            "enc::".parse::<Waypoints>().unwrap_err().to_string()
+        );
+        assert_eq!(
+            "via waypoints cannot be combined with optimize:true",
+            "optimize:true|via:-37.820189,145.149954"
+                .parse::<Waypoints>()
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_display_waypoints() {
+        let inp1 = "-37.820189,145.149954|-37.819681,144.952302";
+        let inp2 = "optimize:true|-37.820189,145.149954";
+        let inp3 = "optimize:true|place_id:ChIJgf0RD69C1moR4OeMIXVWBAU";
+
+        assert_eq!(inp1, inp1.parse::<Waypoints>().unwrap().to_string());
+        assert_eq!(inp2, inp2.parse::<Waypoints>().unwrap().to_string());
+        assert_eq!(inp3, inp3.parse::<Waypoints>().unwrap().to_string());
+    }
+
     #[test]
     fn test_csv_record_to_query() {
-        let inp = CsvRecord {
-            id: "1".to_string(),
-            origin_lat: "-37.820189".to_string(),
-            origin_lon: "145.149954".to_string(),
-            destination_lat: "-37.819681".to_string(),
-            destination_lon: "144.952302".to_string(),
-            departure_time: "1534284000".to_string(),
+        let inp = Record {
             mode: "driving".to_string(),
             avoidances: Option::Some("tolls".to_string()),
             traffic_model: Option::Some("best_guess".to_string()),
+            ..record()
         };
 
         let res = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1537308000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         };
 
         assert_eq!(
             res,
-            Query::from_csv_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0),).unwrap()
+            from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).unwrap()
         );
     }
 
+    #[test]
+    fn test_csv_record_with_alternatives() {
+        let record = |alternatives: &str| Record {
+            alternatives: Option::Some(alternatives.to_string()),
+            ..record()
+        };
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        let res = from_record(record("true"), &now).unwrap();
+        assert!(res.alternatives);
+        assert!(res.to_string().ends_with("&mode=walking&alternatives=true"));
+
+        let res = from_record(record("false"), &now).unwrap();
+        assert!(!res.alternatives);
+        assert!(res.to_string().ends_with("&mode=walking"));
+
+        let res = from_record(record(""), &now).unwrap();
+        assert!(!res.alternatives);
+
+        assert!(from_record(record("yes"), &now).is_err());
+    }
+
+    #[test]
+    fn test_csv_record_with_same_origin_destination() {
+        let record = |destination_lat: &str| Record {
+            destination_lat: destination_lat.to_string(),
+            destination_lon: "145.149954".to_string(),
+            ..record()
+        };
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        assert!(from_record(record("-37.820189"), &now).is_err());
+        // Rounds to the same coordinate when sent
+        assert!(from_record(record("-37.8201891"), &now).is_err());
+        assert!(from_record(record("-37.820188"), &now).is_ok());
+
+        // Compared at the precision the coordinates are sent with
+        let res = Query::from_record(record("-37.8204"), &now, true, 3);
+        assert_eq!(
+            "origin and destination are the same (-37.820,145.150)",
+            res.unwrap_err().to_string()
+        );
+        assert!(Query::from_record(record("-37.8206"), &now, true, 3).is_ok());
+    }
+
+    #[test]
+    fn test_csv_record_with_address() {
+        let inp = Record {
+            origin_lat: "1 Flinders St, Melbourne".to_string(),
+            origin_lon: "".to_string(),
+            mode: "transit".to_string(),
+            ..record()
+        };
+
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).unwrap();
+
+        assert_eq!(
+            Location::Address("1 Flinders St, Melbourne".to_string()),
+            res.origin
+        );
+        assert_eq!(
+            "origin=1%20Flinders%20St,%20Melbourne&destination=-37.819681,144.952302&departure_time=1537308000&mode=transit",
+            res.to_string()
+        );
+    }
+
+    #[test]
+    fn test_csv_record_with_arrival_time() {
+        let inp = Record {
+            departure_time: Option::None,
+            arrival_time: Option::Some("1534284000".to_string()),
+            mode: "transit".to_string(),
+            ..record()
+        };
+
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).unwrap();
+
+        assert_eq!(
+            TimeConstraint::Arrival(ArrivalTime::new(1537308000).unwrap()),
+            res.time
+        );
+    }
+
+    #[test]
+    fn test_traffic_model_with_arrival_time() {
+        let inp = Record {
+            departure_time: Option::None,
+            arrival_time: Option::Some("1534284000".to_string()),
+            mode: "driving".to_string(),
+            traffic_model: Option::Some("best_guess".to_string()),
+            ..record()
+        };
+
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_csv_record_with_language_and_region() {
+        let inp = Record {
+            language: Option::Some("fr".to_string()),
+            region: Option::Some("a u".to_string()),
+            ..record()
+        };
+
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0)).unwrap();
+
+        assert_eq!(Some("fr".to_string()), res.language);
+        assert!(res.to_string().ends_with("&language=fr&region=a%20u"));
+    }
+
+    #[test]
+    fn test_incompatible_avoidance() {
+        let record = |mode: &str, avoidances: &str| Record {
+            departure_time: Option::None,
+            arrival_time: Option::Some("1534284000".to_string()),
+            mode: mode.to_string(),
+            avoidances: Option::Some(avoidances.to_string()),
+            ..record()
+        };
+        let now = NaiveDateTime::from_timestamp(1536991111, 0);
+
+        assert!(from_record(record("driving", "tolls|highways|ferries"), &now).is_ok());
+        assert!(from_record(record("walking", "indoors|ferries"), &now).is_ok());
+        assert!(from_record(record("transit", "ferries"), &now).is_ok());
+        assert_eq!(
+            "avoidance (tolls) cannot be used with mode (walking)",
+            from_record(record("walking", "tolls"), &now).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "avoidance (indoors) cannot be used with mode (driving)",
+            from_record(record("driving", "indoors"), &now).unwrap_err().to_string()
+        );
+        assert!(from_record(record("bicycling", "highways"), &now).is_err());
+    }
+
     #[test]
     fn test_missing_traffic_model() {
-        let inp = CsvRecord {
-            id: "1".to_string(),
-            origin_lat: "-37.820189".to_string(),
-            origin_lon: "145.149954".to_string(),
-            destination_lat: "-37.819681".to_string(),
-            destination_lon: "144.952302".to_string(),
-            departure_time: "1534284000".to_string(),
+        let inp = Record {
             mode: "driving".to_string(),
-            avoidances: Option::None,
-            traffic_model: Option::None,
+            ..record()
         };
 
-        let res = Query::from_csv_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0));
+        let res = from_record(inp, &NaiveDateTime::from_timestamp(1536991111, 0));
         assert!(res.is_err());
     }
 
@@ -515,16 +1909,55 @@ mod tests {
     fn test_to_query() {
         let inp = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1534284000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1534284000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         }.to_string();
 
         let res = "origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1534284000&mode=driving&avoid=tolls&traffic_model=best_guess";
 
         assert_eq!(res, inp);
     }
+
+    /// Queries that are equal render identical strings however their
+    /// multi-valued fields were ordered in the input, with every parameter
+    /// in the canonical order.
+    #[test]
+    fn test_to_query_canonical() {
+        let query = |avoidances: &str, transit_modes: &str| Query {
+            id: "1".to_string(),
+            origin: Location::Address("Box Hill".to_string()),
+            destination: Location::PlaceId("ChIJgf0RD69C1moR4OeMIXVWBAU".to_string()),
+            time: TimeConstraint::Arrival(ArrivalTime::new(1534284000).unwrap()),
+            mode: Mode::Transit,
+            avoidances: Option::Some(avoidances.parse().unwrap()),
+            traffic_model: Option::None,
+            waypoints: Option::Some("-37.8,145.0|-37.9,145.1".parse().unwrap()),
+            units: Option::Some(Units::Metric),
+            language: Option::Some("en".to_string()),
+            region: Option::Some("au".to_string()),
+            transit_modes: Option::Some(transit_modes.parse().unwrap()),
+            transit_routing_preference: Option::Some(TransitRoutingPreference::LessWalking),
+            alternatives: true,
+        };
+
+        let a = query("ferries|tolls", "tram|bus|train");
+        let b = query("tolls|ferries", "train|tram|bus|tram");
+        assert_eq!(a, b);
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(
+            "origin=Box%20Hill&destination=place_id:ChIJgf0RD69C1moR4OeMIXVWBAU&arrival_time=1534284000&mode=transit&avoid=tolls|ferries&waypoints=-37.800000,145.000000|-37.900000,145.100000&units=metric&language=en&region=au&transit_mode=bus|train|tram&transit_routing_preference=less_walking&alternatives=true",
+            a.to_string()
+        );
+    }
 }
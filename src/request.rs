@@ -1,64 +1,1196 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
 use std::mem;
-use std::str;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use futures::{stream, Future, Stream};
-use itertools::Itertools;
-use ratelimit;
+use failure;
+use futures::future::{self, Either, Loop};
+use futures::stream::{Buffered, Map};
+use futures::{stream, Async, Future, IntoFuture, Poll, Stream};
+use reqwest::header::{Headers, RetryAfter, UserAgent};
 use reqwest::unstable::async::{Client, Decoder};
-use tokio_core::reactor::Core;
+use reqwest::{Proxy, StatusCode, Url};
+use serde_json;
+use serde_json::Value;
+use tokio_core::reactor::{Core, Handle, Timeout};
 
-use response::TaggedResponse;
+use jitter::Jitter;
+use output::ResponseSink;
+use query::Mode;
+use response::{Attempts, TaggedResponse};
 use url::TaggedUrl;
 use Result;
 
+/// Statuses that may succeed if the request is sent again.
+const RETRYABLE_STATUSES: [&str; 2] = ["OVER_QUERY_LIMIT", "UNKNOWN_ERROR"];
+
+/// Delay before the first retry, doubled for each subsequent retry.
+const BASE_BACKOFF_MS: u64 = 500;
+
+/// Longest delay between retries when no cap is supplied.
+pub const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+
+/// Longest delay requested by a server's Retry-After header that is honoured.
+const MAX_RETRY_AFTER_SECS: u64 = 60;
+
+/// Time allowed for requests in flight to finish once the deadline passes.
+const DEADLINE_GRACE_SECS: u64 = 5;
+
+/// Number of rate-limit-free chunks over which the rate recovers from one
+/// request per second to the configured limit.
+const RECOVERY_CHUNKS: usize = 10;
+
+/// The parts of a response used to decide whether to retry it.
+#[derive(Debug)]
+struct Reply {
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: Vec<u8>,
+}
+
+/// The final result of sending a request.
+#[derive(Clone, Debug, PartialEq)]
+enum Outcome {
+    Body(String),
+    HttpError(u16),
+    Failed(String),
+    Skipped,
+}
+
+/// Settings controlling how requests are sent.
+#[derive(Debug)]
+pub struct RequestOptions {
+    pub rate_limit: usize,
+    pub concurrency: Option<usize>,
+    pub mode_rate_limits: HashMap<Mode, usize>,
+    pub max_inflight_bytes: Option<usize>,
+    pub max_retries: u32,
+    pub max_backoff: Duration,
+    pub record_attempts: bool,
+    pub progress: bool,
+    pub timeout: Option<u64>,
+    pub deadline: Option<Duration>,
+    pub user_agent: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub proxy: Option<Url>,
+    pub dedup: bool,
+    pub include_url: bool,
+    pub steps: bool,
+    pub fail_fast: bool,
+}
+
+/// A count of completed requests, reported to STDERR as each one resolves.
+struct Progress {
+    completed: usize,
+    total: usize,
+}
+
+impl Progress {
+    fn new(total: usize) -> Progress {
+        Progress {
+            completed: 0,
+            total,
+        }
+    }
+
+    /// Record a completed request and overwrite the previous report.
+    fn advance(&mut self) {
+        self.completed += 1;
+        eprint!("\r{}", self.report());
+    }
+
+    /// End the report, leaving the final count on its own line.
+    fn finish(&self) {
+        eprintln!("\r{}", self.report());
+    }
+
+    fn report(&self) -> String {
+        format!("{}/{} requests completed", self.completed, self.total)
+    }
+}
+
+/// Adapts the number of requests sent each second to rate limiting by the
+/// server. The rate is halved after any chunk in which a request was rate
+/// limited, and otherwise recovers gradually towards the configured limit.
+#[derive(Debug)]
+struct Throttle {
+    max_rate: usize,
+    rate: usize,
+    limited: bool,
+    retry_after: Option<Duration>,
+}
+
+impl Throttle {
+    fn new(max_rate: usize) -> Throttle {
+        Throttle {
+            max_rate,
+            rate: max_rate,
+            limited: false,
+            retry_after: None,
+        }
+    }
+
+    /// The number of requests to send in the next chunk.
+    fn rate(&self) -> usize {
+        self.rate
+    }
+
+    /// Record the outcome of an attempt, keeping the longest delay requested
+    /// by the server.
+    fn observe(&mut self, limited: bool, retry_after: Option<Duration>) {
+        self.limited |= limited;
+        self.retry_after = cmp::max(self.retry_after, retry_after);
+    }
+
+    /// Adjust the rate at the end of a chunk, returning any delay requested
+    /// by the server before the next one is sent.
+    fn adjust(&mut self) -> Option<Duration> {
+        self.rate = if self.limited {
+            cmp::max(self.rate / 2, 1)
+        } else {
+            let step = cmp::max(self.max_rate / RECOVERY_CHUNKS, 1);
+            cmp::min(self.rate + step, self.max_rate)
+        };
+        self.limited = false;
+        self.retry_after.take()
+    }
+}
+
+/// Send the requests, returning their responses in input order. If a sink is
+/// supplied, each response is instead written to it as soon as it arrives,
+/// rather than in input order, and none are returned.
 pub fn execute_requests(
     requests: &[TaggedUrl],
-    rate_limit: usize,
+    options: &RequestOptions,
+    chunk_jitter: Option<Jitter>,
+    retry_jitter: Option<Jitter>,
+    sink: Option<&mut ResponseSink>,
 ) -> Result<Vec<TaggedResponse>> {
-    // Build rate limiter
-    let mut ratelimit = ratelimit::Builder::new()
-        .capacity(1)
-        .quantum(1)
-        .interval(Duration::new(1, 0))
-        .build();
-
     // Build event loop
     let mut core = Core::new()?;
+    let handle = core.handle();
 
     // Build request client
-    let client = Client::new(&core.handle());
-
-    // Retrieve iterator of IDs
-    let id_iter = requests.iter().map(|r| r.id.clone());
-
-    // Send the requests in chunks
-    let mut responses: Vec<String> = Vec::with_capacity(requests.len());
-
-    let url_iter = requests.iter().map(|r| r.url.clone());
-    for chunk in &url_iter.into_iter().chunks(rate_limit) {
-        let bodies = stream::iter_ok(chunk)
-            .map(|url| {
-                client.get(url).send().and_then(|mut res| {
-                    let body = mem::replace(res.body_mut(), Decoder::empty());
-                    body.concat2().from_err()
-                })
-            }).buffered(rate_limit);
-
-        let work = bodies.for_each(|b| {
-            responses.push(str::from_utf8(&b).unwrap().to_string());
+    let client = build_client(&handle, options)?;
+
+    // Only send the first of any requests with identical URLs, unless asked
+    // to send them all, and share its response with the others
+    let sources = if options.dedup {
+        deduplicate(requests)
+    } else {
+        (0..requests.len()).collect()
+    };
+    let mut sharers: Vec<Vec<usize>> = vec![Vec::new(); requests.len()];
+    for (i, &source) in sources.iter().enumerate() {
+        sharers[source].push(i);
+    }
+    let distinct = sharers.iter().filter(|s| !s.is_empty()).count();
+
+    // Group requests by mode, in order of first appearance, so that each
+    // mode can be held to its own rate limit
+    let mut groups: Vec<Group> = Vec::new();
+    for (i, request) in requests.iter().enumerate() {
+        if sources[i] != i {
+            continue;
+        }
+        match groups.iter().position(|g| g.mode == request.mode) {
+            Some(pos) => groups[pos].indices.push(i),
+            None => groups.push(Group::new(request.mode, i)),
+        }
+    }
+
+    // Share each response with any duplicates of its request, then either
+    // write it out or hold on to it until every request has been sent
+    let progress = RefCell::new(if options.progress {
+        Some(Progress::new(distinct))
+    } else {
+        None
+    });
+    let responses: RefCell<Vec<Option<TaggedResponse>>> =
+        RefCell::new(vec![None; requests.len()]);
+    let sink = RefCell::new(sink);
+    let respond = |source: usize, outcome: Outcome, attempts: Attempts| -> Result<()> {
+        for &i in &sharers[source] {
+            let response = tag_response(&requests[i], &outcome, &attempts, options);
+            let sent = match outcome {
+                Outcome::Skipped => false,
+                _ => true,
+            };
+            if options.fail_fast && sent && !response.is_success() {
+                Err(RequestError::FailFast {
+                    id: response.id.clone(),
+                    status: response.status().to_string(),
+                })?
+            }
+            match *sink.borrow_mut() {
+                Some(ref mut sink) => sink.write(&response)?,
+                None => responses.borrow_mut()[i] = Some(response),
+            }
+        }
+        if let Some(ref mut progress) = *progress.borrow_mut() {
+            progress.advance();
+        }
+        Ok(())
+    };
+
+    // Send the requests for every mode at the same time, each held to its
+    // own rate limit
+    let sender = Sender {
+        handle,
+        client,
+        options,
+        inflight_bytes: Arc::new(AtomicUsize::new(0)),
+        chunk_jitter: RefCell::new(chunk_jitter),
+        retry_jitter: RefCell::new(retry_jitter),
+        deadline: options.deadline.map(|d| Instant::now() + d),
+    };
+    let work = future::join_all(
+        groups
+            .iter()
+            .map(|group| sender.send(requests, group, &respond)),
+    );
+
+    // Requests still in flight when the deadline passes are given a short
+    // grace period to finish, then abandoned
+    match sender.deadline {
+        Some(deadline) => {
+            let cutoff = deadline + Duration::from_secs(DEADLINE_GRACE_SECS);
+            let now = Instant::now();
+            let remaining = if cutoff > now {
+                cutoff - now
+            } else {
+                Duration::from_secs(0)
+            };
+            let cutoff = Timeout::new(remaining, &sender.handle)?;
+            match core.run(work.select2(cutoff)) {
+                Ok(_) => (),
+                Err(Either::A((err, _))) => Err(err)?,
+                Err(Either::B((err, _))) => Err(err)?,
+            }
+        }
+        None => {
+            core.run(work)?;
+        }
+    }
+
+    // Any requests that were not sent, or did not finish, before the
+    // deadline are marked as skipped
+    for group in &groups {
+        for &i in &group.indices[group.responded.get()..] {
+            respond(i, Outcome::Skipped, Attempts::new())?;
+        }
+    }
+    if let Some(progress) = progress.into_inner() {
+        progress.finish();
+    }
+
+    Ok(responses.into_inner().into_iter().flat_map(|r| r).collect())
+}
+
+/// Tag the outcome of a request with its id, noting the attempts made and
+/// the URL sent when requested.
+fn tag_response(
+    request: &TaggedUrl,
+    outcome: &Outcome,
+    attempts: &Attempts,
+    options: &RequestOptions,
+) -> TaggedResponse {
+    let mut response = match outcome {
+        Outcome::Body(body) => TaggedResponse::new(&request.id, body),
+        Outcome::HttpError(code) => TaggedResponse::http_error(&request.id, *code),
+        Outcome::Failed(message) => TaggedResponse::request_error(&request.id, message),
+        Outcome::Skipped => TaggedResponse::skipped(&request.id),
+    };
+    if options.record_attempts {
+        response = response.with_attempts(attempts.clone());
+    }
+    if options.include_url {
+        response = response.with_request_url(request.redacted_url().as_str());
+    }
+    if options.steps {
+        response = response.with_steps();
+    }
+    response
+}
+
+/// For each request, find the index of the first request with the same URL,
+/// which may be itself.
+fn deduplicate(requests: &[TaggedUrl]) -> Vec<usize> {
+    let mut first: HashMap<&str, usize> = HashMap::new();
+    requests
+        .iter()
+        .enumerate()
+        .map(|(i, request)| *first.entry(request.url.as_str()).or_insert(i))
+        .collect()
+}
+
+/// Build a client that sends the configured headers with every request,
+/// through the configured proxy if there is one.
+fn build_client(handle: &Handle, options: &RequestOptions) -> Result<Client> {
+    let mut headers = Headers::new();
+    if let Some(ref user_agent) = options.user_agent {
+        headers.set(UserAgent::new(user_agent.clone()));
+    }
+    for (name, value) in &options.headers {
+        headers.append_raw(name.clone(), value.as_bytes().to_vec());
+    }
+    let mut builder = Client::builder();
+    builder.default_headers(headers);
+    if let Some(ref proxy) = options.proxy {
+        builder.proxy(Proxy::all(proxy.clone())?);
+    }
+    Ok(builder.build(handle)?)
+}
+
+/// The requests for a single mode, in input order, along with the number
+/// that have been responded to so far.
+struct Group {
+    mode: Mode,
+    indices: Vec<usize>,
+    responded: Cell<usize>,
+}
+
+impl Group {
+    fn new(mode: Mode, first: usize) -> Group {
+        Group {
+            mode,
+            indices: vec![first],
+            responded: Cell::new(0),
+        }
+    }
+}
+
+/// State shared by the requests for every mode, which are sent together on
+/// the same event loop.
+struct Sender<'a> {
+    handle: Handle,
+    client: Client,
+    options: &'a RequestOptions,
+    inflight_bytes: Arc<AtomicUsize>,
+    chunk_jitter: RefCell<Option<Jitter>>,
+    retry_jitter: RefCell<Option<Jitter>>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Sender<'a> {
+    /// Send the requests for a mode, passing the index and outcome of each
+    /// to `respond` in order.
+    fn send(
+        &'a self,
+        requests: &'a [TaggedUrl],
+        group: &'a Group,
+        respond: &'a Fn(usize, Outcome, Attempts) -> Result<()>,
+    ) -> impl Future<Item = (), Error = failure::Error> + 'a {
+        // Requests are sent in chunks of up to the mode's rate limit, or the
+        // global one if it has none, and one chunk is started each second,
+        // so the effective rate matches the limit. Chunks are spaced by their
+        // start times rather than their completion times. Within a chunk, no
+        // more than `concurrency` requests are in flight at once. Chunks
+        // shrink while the server is rate limiting requests, and grow back
+        // once it stops.
+        let rate_limit = *self
+            .options
+            .mode_rate_limits
+            .get(&group.mode)
+            .unwrap_or(&self.options.rate_limit);
+        let concurrency = self.options.concurrency.unwrap_or(rate_limit);
+        let throttle = Arc::new(Mutex::new(Throttle::new(rate_limit)));
+
+        let total = group.indices.len();
+        future::loop_fn((0, None), move |(position, last_start)| {
+            if position >= total {
+                return Either::A(future::ok(Loop::Break(())));
+            }
+
+            // Wait out whatever is left of the second since the last chunk
+            // started, or for as long as the server asks if that is longer
+            let wait = match last_start {
+                Some(last_start) => {
+                    let retry_after = throttle.lock().unwrap().adjust().unwrap_or_default();
+                    let now = Instant::now();
+                    cmp::max(now + retry_after, last_start + Duration::from_secs(1)) - now
+                }
+                None => Duration::from_secs(0),
+            };
+            let end = cmp::min(position + throttle.lock().unwrap().rate(), total);
+            let chunk = group.indices[position..end]
+                .iter()
+                .map(|&i| requests[i].url.clone())
+                .collect::<Vec<_>>();
+            let started = sleep(wait, &self.handle).and_then(move |_| {
+                // Stop sending requests once the deadline has passed
+                if self.deadline.map_or(false, |d| Instant::now() >= d) {
+                    return Either::A(future::ok(None));
+                }
+
+                // Delay the chunk by a random amount, without counting the
+                // delay towards the spacing of chunks
+                let start = Instant::now();
+                let jitter = match *self.chunk_jitter.borrow_mut() {
+                    Some(ref mut jitter) => jitter.delay(),
+                    None => Duration::from_secs(0),
+                };
+                Either::B(sleep(jitter, &self.handle).map(move |_| Some(start)))
+            });
+            let throttle = Arc::clone(&throttle);
+            Either::B(started.and_then(move |start| match start {
+                Some(start) => Either::A(
+                    self.send_chunk(chunk, concurrency, throttle, group, respond)
+                        .map(move |_| Loop::Continue((end, Some(start)))),
+                ),
+                None => Either::B(future::ok(Loop::Break(()))),
+            }))
+        })
+    }
+
+    /// Send a chunk of requests and pass their outcomes to `respond` in
+    /// order.
+    fn send_chunk(
+        &'a self,
+        urls: Vec<Url>,
+        concurrency: usize,
+        throttle: Arc<Mutex<Throttle>>,
+        group: &'a Group,
+        respond: &'a Fn(usize, Outcome, Attempts) -> Result<()>,
+    ) -> impl Future<Item = (), Error = failure::Error> + 'a {
+        let urls = ByteGate::new(
+            stream::iter_ok(urls),
+            Arc::clone(&self.inflight_bytes),
+            self.options.max_inflight_bytes,
+        );
+        let bodies = dispatch(urls, concurrency, move |url| {
+            self.send_one(url, Arc::clone(&throttle))
+        });
+        bodies.for_each(move |(res, attempts)| {
+            let outcome = match res {
+                Ok(reply) => {
+                    self.inflight_bytes.fetch_sub(reply.body.len(), Ordering::SeqCst);
+                    if reply.status.is_success() {
+                        Outcome::Body(String::from_utf8_lossy(&reply.body).into_owned())
+                    } else {
+                        Outcome::HttpError(reply.status.as_u16())
+                    }
+                }
+                Err(err) => Outcome::Failed(err.to_string()),
+            };
+            let position = group.responded.get();
+            respond(group.indices[position], outcome, attempts)?;
+            group.responded.set(position + 1);
             Ok(())
+        })
+    }
+
+    /// Send a single request until it succeeds or the retries run out,
+    /// backing off exponentially between attempts, or for as long as the
+    /// server asks if that is longer. Errors from the final attempt are
+    /// passed on rather than failing the run.
+    fn send_one(
+        &'a self,
+        url: Url,
+        throttle: Arc<Mutex<Throttle>>,
+    ) -> impl Future<Item = (Fetched, Attempts), Error = failure::Error> + 'a {
+        let max_retries = self.options.max_retries;
+        future::loop_fn(Attempts::new(), move |mut attempts| {
+            let inflight_bytes = Arc::clone(&self.inflight_bytes);
+            let throttle = Arc::clone(&throttle);
+            let request = fetch_within(
+                &self.client,
+                url.clone(),
+                inflight_bytes,
+                self.options.timeout,
+                &self.handle,
+            );
+            request.then(move |res| {
+                let retry_after = res.as_ref().ok().and_then(|reply| reply.retry_after);
+                throttle
+                    .lock()
+                    .unwrap()
+                    .observe(is_rate_limited(&res), retry_after);
+                if !record_attempt(&res, &mut attempts, max_retries) {
+                    return Either::A(future::ok(Loop::Break((res, attempts))));
+                }
+
+                // The body of a failed attempt is discarded
+                if let Ok(ref reply) = res {
+                    self.inflight_bytes.fetch_sub(reply.body.len(), Ordering::SeqCst);
+                }
+                let backoff = retry_delay(
+                    attempts.count,
+                    self.options.max_backoff,
+                    &mut self.retry_jitter.borrow_mut(),
+                );
+                let delay = cmp::max(backoff, retry_after.unwrap_or_default());
+                Either::B(sleep(delay, &self.handle).map(|_| Loop::Continue(attempts)))
+            })
+        })
+    }
+}
+
+/// A response, or the error that prevented one.
+type Fetched = ::std::result::Result<Reply, failure::Error>;
+
+/// Send a single request, counting the bytes of its body as they arrive, both
+/// in total and for this request alone.
+fn fetch(
+    client: &Client,
+    url: Url,
+    inflight_bytes: Arc<AtomicUsize>,
+    received: Arc<AtomicUsize>,
+) -> impl Future<Item = Reply, Error = ::reqwest::Error> {
+    client.get(url).send().and_then(move |mut res| {
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get::<RetryAfter>()
+            .map(|h| retry_after_delay(h, SystemTime::now()));
+        let body = mem::replace(res.body_mut(), Decoder::empty());
+        body.fold(Vec::new(), move |mut acc, chunk| {
+            inflight_bytes.fetch_add(chunk.len(), Ordering::SeqCst);
+            received.fetch_add(chunk.len(), Ordering::SeqCst);
+            acc.extend_from_slice(&chunk);
+            Ok::<_, ::reqwest::Error>(acc)
+        }).map(move |body| Reply {
+            status,
+            retry_after,
+            body,
+        })
+    })
+}
+
+/// Send a single request, failing it if it does not complete within the
+/// timeout. The bytes of a partially received body are released on failure.
+fn fetch_within(
+    client: &Client,
+    url: Url,
+    inflight_bytes: Arc<AtomicUsize>,
+    timeout: Option<u64>,
+    handle: &Handle,
+) -> impl Future<Item = Reply, Error = failure::Error> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let request = fetch(client, url, Arc::clone(&inflight_bytes), Arc::clone(&received))
+        .map_err(failure::Error::from);
+
+    let request = match timeout {
+        Some(secs) => {
+            let timer = sleep(Duration::from_secs(secs), handle);
+            Either::A(request.select2(timer).then(move |res| match res {
+                Ok(Either::A((item, _))) => Ok(item),
+                Ok(Either::B(_)) => Err(RequestError::Timeout { secs }.into()),
+                Err(Either::A((err, _))) | Err(Either::B((err, _))) => Err(err),
+            }))
+        }
+        None => Either::B(request),
+    };
+
+    request.map_err(move |err| {
+        inflight_bytes.fetch_sub(received.load(Ordering::SeqCst), Ordering::SeqCst);
+        err
+    })
+}
+
+/// Record the outcome of an attempt, returning whether the request should be
+/// sent again. Failed attempts are retried while they are transient and the
+/// retries have not run out.
+fn record_attempt<E: ToString>(
+    res: &::std::result::Result<Reply, E>,
+    attempts: &mut Attempts,
+    max_retries: u32,
+) -> bool {
+    let reason = match res {
+        Ok(reply) if !reply.status.is_success() => {
+            Some(format!("HTTP_ERROR {}", reply.status.as_u16()))
+        }
+        Ok(reply) => retryable_status(&reply.body),
+        Err(err) => Some(err.to_string()),
+    };
+    match reason {
+        Some(reason) => {
+            attempts.record_failure(&reason);
+            attempts.count <= max_retries && is_transient(res)
+        }
+        None => {
+            attempts.record_success();
+            false
+        }
+    }
+}
+
+/// Whether a failed attempt may succeed if sent again. Network errors,
+/// server errors and rate limiting are transient, whereas other HTTP errors
+/// such as a 403 will recur.
+fn is_transient<E>(res: &::std::result::Result<Reply, E>) -> bool {
+    match res {
+        Ok(reply) => {
+            let status = reply.status;
+            status.is_success() || status.is_server_error() || status.as_u16() == 429
+        }
+        Err(_) => true,
+    }
+}
+
+/// Whether an attempt was turned away because of rate limiting, either with
+/// a 429 or an OVER_QUERY_LIMIT status.
+fn is_rate_limited<E>(res: &::std::result::Result<Reply, E>) -> bool {
+    match res {
+        Ok(reply) if reply.status.as_u16() == 429 => true,
+        Ok(reply) if reply.status.is_success() => {
+            retryable_status(&reply.body).map_or(false, |s| s == "OVER_QUERY_LIMIT")
+        }
+        _ => false,
+    }
+}
+
+/// The delay requested by a Retry-After header, measured from `now` when
+/// the header gives a date, and capped at `MAX_RETRY_AFTER_SECS`.
+fn retry_after_delay(header: &RetryAfter, now: SystemTime) -> Duration {
+    let delay = match header {
+        RetryAfter::Delay(delay) => *delay,
+        RetryAfter::DateTime(date) => SystemTime::from(*date)
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    cmp::min(delay, Duration::from_secs(MAX_RETRY_AFTER_SECS))
+}
+
+/// Return the status of a response body if it indicates the request should
+/// be retried.
+fn retryable_status(body: &[u8]) -> Option<String> {
+    let value = serde_json::from_slice::<Value>(body).ok()?;
+    let status = value["status"].as_str()?;
+    if RETRYABLE_STATUSES.contains(&status) {
+        Some(status.to_string())
+    } else {
+        None
+    }
+}
+
+/// The delay before the given retry, starting from one.
+fn backoff(retry: u32) -> Duration {
+    let factor = 2u64.saturating_pow(retry.saturating_sub(1));
+    Duration::from_millis(BASE_BACKOFF_MS.saturating_mul(factor))
+}
+
+/// The delay before the given retry: the exponential backoff, capped at the
+/// maximum, then drawn uniformly from zero up to that when jitter is used.
+/// Jitter stops requests that failed together from retrying together.
+fn retry_delay(retry: u32, max_backoff: Duration, jitter: &mut Option<Jitter>) -> Duration {
+    let delay = cmp::min(backoff(retry), max_backoff);
+    match jitter {
+        Some(jitter) => jitter.delay_up_to(delay),
+        None => delay,
+    }
+}
+
+/// Wait for the given time without holding up the event loop.
+fn sleep(duration: Duration, handle: &Handle) -> impl Future<Item = (), Error = failure::Error> {
+    Timeout::new(duration, handle)
+        .into_future()
+        .flatten()
+        .map_err(failure::Error::from)
+}
+
+/// Resolve the future produced for each item, yielding the results in order,
+/// with no more than `concurrency` futures in flight at once.
+fn dispatch<S, F, U>(items: S, concurrency: usize, fetch: F) -> Buffered<Map<S, F>>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> U,
+    U: IntoFuture<Error = S::Error>,
+{
+    items.map(fetch).buffered(concurrency)
+}
+
+/// Stream adapter that holds back further items while the shared count of
+/// in-flight response bytes exceeds the supplied limit.
+///
+/// The byte count can only be non-zero while response bodies belonging to
+/// the same task are outstanding, and their progress will wake the task, so
+/// no explicit notification is needed when the gate closes.
+struct ByteGate<S> {
+    inner: S,
+    inflight_bytes: Arc<AtomicUsize>,
+    max_inflight_bytes: Option<usize>,
+}
+
+impl<S> ByteGate<S> {
+    fn new(
+        inner: S,
+        inflight_bytes: Arc<AtomicUsize>,
+        max_inflight_bytes: Option<usize>,
+    ) -> ByteGate<S> {
+        ByteGate {
+            inner,
+            inflight_bytes,
+            max_inflight_bytes,
+        }
+    }
+}
+
+impl<S: Stream> Stream for ByteGate<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if let Some(max) = self.max_inflight_bytes {
+            if self.inflight_bytes.load(Ordering::SeqCst) > max {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.inner.poll()
+    }
+}
+
+#[derive(Debug, Fail)]
+enum RequestError {
+    #[fail(display = "request timed out after {} second(s)", secs)]
+    Timeout { secs: u64 },
+    #[fail(display = "stopping after request for id ({}) failed with status {}", id, status)]
+    FailFast { id: String, status: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+    use futures::{future, task};
+    use reqwest::header::HttpDate;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn reply(code: u16, body: &str) -> Reply {
+        Reply {
+            status: StatusCode::try_from(code).unwrap(),
+            retry_after: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /// A future that counts itself as in flight from its first poll until
+    /// it completes a few polls later.
+    struct Tracked {
+        polls: usize,
+        inflight: Arc<AtomicUsize>,
+        max_inflight: Arc<AtomicUsize>,
+    }
+
+    impl Future for Tracked {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<(), ()> {
+            if self.polls == 0 {
+                let inflight = self.inflight.fetch_add(1, Ordering::SeqCst) + 1;
+                if inflight > self.max_inflight.load(Ordering::SeqCst) {
+                    self.max_inflight.store(inflight, Ordering::SeqCst);
+                }
+            }
+            self.polls += 1;
+            if self.polls < 3 {
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+            self.inflight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Serve an empty JSON object in response to every request on a local
+    /// port, recording the target of each request and when it arrived.
+    fn serve() -> (String, Arc<Mutex<Vec<(String, Instant)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&arrivals);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let target = request.split_whitespace().nth(1).unwrap_or("").to_string();
+                recorded.lock().unwrap().push((target, Instant::now()));
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+                );
+            }
         });
+        (base, arrivals)
+    }
+
+    /// A mode with its own rate limit is held to it, without holding up the
+    /// requests for other modes.
+    #[test]
+    fn test_mode_rate_limits() {
+        let (base, arrivals) = serve();
+        let request = |id: &str, mode: Mode| TaggedUrl {
+            id: id.to_string(),
+            mode,
+            url: format!("{}/?id={}", base, id).parse().unwrap(),
+        };
+        let requests = vec![
+            request("d1", Mode::Driving),
+            request("d2", Mode::Driving),
+            request("d3", Mode::Driving),
+            request("t1", Mode::Transit),
+            request("t2", Mode::Transit),
+            request("t3", Mode::Transit),
+        ];
+        let mut mode_rate_limits = HashMap::new();
+        mode_rate_limits.insert(Mode::Driving, 1);
+
+        let start = Instant::now();
+        let options = RequestOptions {
+            rate_limit: 10,
+            concurrency: None,
+            mode_rate_limits,
+            max_inflight_bytes: None,
+            max_retries: 0,
+            max_backoff: Duration::from_secs(1),
+            record_attempts: false,
+            progress: false,
+            timeout: None,
+            deadline: None,
+            user_agent: None,
+            headers: Vec::new(),
+            proxy: None,
+            dedup: true,
+            include_url: false,
+            steps: false,
+            fail_fast: false,
+        };
+        let res = execute_requests(&requests, &options, None, None, None).unwrap();
+        assert_eq!(6, res.len());
 
-        core.run(work)?;
-        ratelimit.wait();
+        let arrivals = arrivals.lock().unwrap();
+        let elapsed = |id: &str| {
+            let target = format!("/?id={}", id);
+            let arrival = arrivals.iter().find(|a| a.0 == target).unwrap();
+            arrival.1 - start
+        };
+
+        // One driving request is sent each second
+        assert!(elapsed("d2") >= Duration::from_secs(1));
+        assert!(elapsed("d3") >= Duration::from_secs(2));
+
+        // Transit requests are all sent at once, alongside the first
+        assert!(elapsed("t3") < Duration::from_secs(1));
     }
 
-    // Zip ids back to corresponding responses
-    let tagged_responses = id_iter
-        .zip(responses.iter())
-        .map(|t| TaggedResponse::new(&t.0, t.1))
-        .collect::<Vec<_>>();
+    #[test]
+    fn test_retryable_status() {
+        assert_eq!(
+            Some("OVER_QUERY_LIMIT".to_string()),
+            retryable_status(br#"{"status": "OVER_QUERY_LIMIT"}"#)
+        );
+        assert_eq!(
+            Some("UNKNOWN_ERROR".to_string()),
+            retryable_status(br#"{"status": "UNKNOWN_ERROR"}"#)
+        );
+        assert_eq!(None, retryable_status(br#"{"status": "OK"}"#));
+        assert_eq!(None, retryable_status(br#"{"status": "ZERO_RESULTS"}"#));
+        assert_eq!(None, retryable_status(br#"{"status": "NOT_FOUND"}"#));
+        assert_eq!(None, retryable_status(b"<html>"));
+    }
 
-    Ok(tagged_responses)
+    #[test]
+    fn test_is_transient() {
+        let res = |code| Ok::<_, ()>(reply(code, ""));
+
+        assert!(is_transient(&res(200)));
+        assert!(is_transient(&res(429)));
+        assert!(is_transient(&res(503)));
+        assert!(!is_transient(&res(403)));
+        assert!(!is_transient(&res(404)));
+        assert!(is_transient(&Err::<Reply, _>(())));
+    }
+
+    /// Failed attempts are recorded and retried until one succeeds.
+    #[test]
+    fn test_record_attempt() {
+        let replies = vec![
+            reply(200, r#"{"status": "OVER_QUERY_LIMIT"}"#),
+            reply(503, ""),
+            reply(200, r#"{"status": "OK"}"#),
+        ];
+        let mut attempts = Attempts::new();
+        let retries = replies
+            .into_iter()
+            .map(|reply| record_attempt(&Ok::<_, String>(reply), &mut attempts, 3))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![true, true, false], retries);
+        assert_eq!(3, attempts.count);
+        assert_eq!(vec!["OVER_QUERY_LIMIT", "HTTP_ERROR 503"], attempts.failures);
+
+        // Retries stop once they run out, or when the failure will recur
+        let mut attempts = Attempts::new();
+        assert!(!record_attempt(&Ok::<_, String>(reply(503, "")), &mut attempts, 0));
+        let mut attempts = Attempts::new();
+        assert!(!record_attempt(&Ok::<_, String>(reply(403, "")), &mut attempts, 3));
+        assert_eq!(vec!["HTTP_ERROR 403"], attempts.failures);
+        let mut attempts = Attempts::new();
+        assert!(record_attempt(&Err::<Reply, _>("connection reset"), &mut attempts, 3));
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        let res = |code, body| Ok::<_, ()>(reply(code, body));
+
+        assert!(is_rate_limited(&res(429, "")));
+        assert!(is_rate_limited(&res(200, r#"{"status": "OVER_QUERY_LIMIT"}"#)));
+        assert!(!is_rate_limited(&res(200, r#"{"status": "UNKNOWN_ERROR"}"#)));
+        assert!(!is_rate_limited(&res(200, r#"{"status": "OK"}"#)));
+        assert!(!is_rate_limited(&res(503, "")));
+        assert!(!is_rate_limited(&Err::<Reply, _>(())));
+    }
+
+    #[test]
+    fn test_retry_after_delay() {
+        let now = SystemTime::now();
+        let at = |secs| RetryAfter::DateTime(HttpDate::from(now + Duration::from_secs(secs)));
+
+        assert_eq!(
+            Duration::from_secs(5),
+            retry_after_delay(&RetryAfter::Delay(Duration::from_secs(5)), now)
+        );
+        assert_eq!(
+            Duration::from_secs(MAX_RETRY_AFTER_SECS),
+            retry_after_delay(&RetryAfter::Delay(Duration::from_secs(3600)), now)
+        );
+
+        // Dates are only precise to the second, and dates in the past mean
+        // there is no need to wait
+        assert!(retry_after_delay(&at(10), now) > Duration::from_secs(8));
+        let past = RetryAfter::DateTime(HttpDate::from(now));
+        assert_eq!(
+            Duration::from_secs(0),
+            retry_after_delay(&past, now + Duration::from_secs(10))
+        );
+    }
+
+    /// Feed the throttle the replies to a run of chunks: a burst of rate
+    /// limiting halves the rate, and it then recovers step by step.
+    #[test]
+    fn test_throttle() {
+        let limited = || reply(200, r#"{"status": "OVER_QUERY_LIMIT"}"#);
+        let ok = || reply(200, r#"{"status": "OK"}"#);
+        let after = |secs| Reply {
+            retry_after: Some(Duration::from_secs(secs)),
+            ..reply(429, "")
+        };
+        let chunks = vec![
+            vec![ok()],
+            vec![ok(), limited()],
+            vec![after(2), after(1)],
+            vec![limited()],
+            vec![ok()],
+            vec![reply(503, "")],
+        ];
+
+        let throttle = Arc::new(Mutex::new(Throttle::new(40)));
+        let mut rates = Vec::new();
+        let mut delays = Vec::new();
+        for chunk in chunks {
+            let observed = Arc::clone(&throttle);
+            stream::iter_ok::<_, ()>(chunk)
+                .for_each(move |reply| {
+                    let retry_after = reply.retry_after;
+                    let res = Ok::<_, ()>(reply);
+                    observed
+                        .lock()
+                        .unwrap()
+                        .observe(is_rate_limited(&res), retry_after);
+                    Ok(())
+                }).wait()
+                .unwrap();
+            let mut throttle = throttle.lock().unwrap();
+            delays.push(throttle.adjust());
+            rates.push(throttle.rate());
+        }
+
+        assert_eq!(vec![40, 20, 10, 5, 9, 13], rates);
+        assert_eq!(
+            vec![None, None, Some(Duration::from_secs(2)), None, None, None],
+            delays
+        );
+    }
+
+    #[test]
+    fn test_throttle_bounds() {
+        let mut throttle = Throttle::new(3);
+        for _ in 0..5 {
+            throttle.observe(true, None);
+            throttle.adjust();
+        }
+        assert_eq!(1, throttle.rate());
+
+        for _ in 0..5 {
+            throttle.adjust();
+        }
+        assert_eq!(3, throttle.rate());
+    }
+
+    #[test]
+    fn test_progress_report() {
+        let mut progress = Progress::new(3);
+        assert_eq!("0/3 requests completed", progress.report());
+
+        progress.completed += 2;
+        assert_eq!("2/3 requests completed", progress.report());
+    }
+
+    /// Requests not sent before the deadline are still given a response.
+    #[test]
+    fn test_deadline_passed() {
+        let requests = ["1", "2", "3"]
+            .iter()
+            .map(|id| TaggedUrl {
+                id: id.to_string(),
+                mode: Mode::Driving,
+                url: "http://localhost:9/".parse().unwrap(),
+            }).collect::<Vec<_>>();
+        let options = RequestOptions {
+            rate_limit: 2,
+            concurrency: None,
+            mode_rate_limits: HashMap::new(),
+            max_inflight_bytes: None,
+            max_retries: 0,
+            max_backoff: Duration::from_secs(1),
+            record_attempts: false,
+            progress: false,
+            timeout: None,
+            deadline: Some(Duration::from_secs(0)),
+            user_agent: None,
+            headers: Vec::new(),
+            proxy: None,
+            dedup: true,
+            include_url: false,
+            steps: false,
+            fail_fast: true,
+        };
+
+        let res = execute_requests(&requests, &options, None, None, None).unwrap();
+        assert_eq!(
+            vec![("1", "SKIPPED"), ("2", "SKIPPED"), ("3", "SKIPPED")],
+            res.iter()
+                .map(|r| (r.id.as_str(), r.status()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let request = |id: &str, url: &str| TaggedUrl {
+            id: id.to_string(),
+            mode: Mode::Driving,
+            url: url.parse().unwrap(),
+        };
+        let requests = vec![
+            request("1", "https://example.com/?origin=a"),
+            request("2", "https://example.com/?origin=b"),
+            request("3", "https://example.com/?origin=a"),
+            request("4", "https://example.com/?origin=b"),
+            request("5", "https://example.com/?origin=c"),
+        ];
+
+        assert_eq!(vec![0, 1, 0, 1, 4], deduplicate(&requests));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_millis(500), backoff(1));
+        assert_eq!(Duration::from_millis(1000), backoff(2));
+        assert_eq!(Duration::from_millis(2000), backoff(3));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let max_backoff = Duration::from_millis(3000);
+        assert_eq!(Duration::from_millis(2000), retry_delay(3, max_backoff, &mut None));
+        assert_eq!(Duration::from_millis(3000), retry_delay(5, max_backoff, &mut None));
+
+        let mut jitter = Some(Jitter::new(3000, Some(42)));
+        for retry in 1..10 {
+            let bound = cmp::min(backoff(retry), max_backoff);
+            assert!(retry_delay(retry, max_backoff, &mut jitter) <= bound);
+        }
+
+        // Seeded jitter gives the same delays
+        let mut jitter1 = Some(Jitter::new(3000, Some(7)));
+        let mut jitter2 = Some(Jitter::new(3000, Some(7)));
+        assert_eq!(
+            (1..10)
+                .map(|r| retry_delay(r, max_backoff, &mut jitter1))
+                .collect::<Vec<_>>(),
+            (1..10)
+                .map(|r| retry_delay(r, max_backoff, &mut jitter2))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_dispatch_concurrency() {
+        let inflight = Arc::new(AtomicUsize::new(0));
+        let max_inflight = Arc::new(AtomicUsize::new(0));
+
+        let results = dispatch(stream::iter_ok::<_, ()>(0..20), 4, |_| Tracked {
+            polls: 0,
+            inflight: Arc::clone(&inflight),
+            max_inflight: Arc::clone(&max_inflight),
+        }).collect()
+        .wait()
+        .unwrap();
+
+        assert_eq!(20, results.len());
+        assert_eq!(4, max_inflight.load(Ordering::SeqCst));
+        assert_eq!(0, inflight.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_byte_gate() {
+        let inflight_bytes = Arc::new(AtomicUsize::new(0));
+        let mut gate = ByteGate::new(
+            stream::iter_ok::<_, ()>(vec![1, 2]),
+            Arc::clone(&inflight_bytes),
+            Some(100),
+        );
+
+        future::lazy(|| {
+            // Below the limit, items pass through
+            assert_eq!(Ok(Async::Ready(Some(1))), gate.poll());
+
+            // Above the limit, dispatch pauses
+            inflight_bytes.store(101, Ordering::SeqCst);
+            assert_eq!(Ok(Async::NotReady), gate.poll());
+
+            // Once bytes are released, dispatch resumes
+            inflight_bytes.store(100, Ordering::SeqCst);
+            assert_eq!(Ok(Async::Ready(Some(2))), gate.poll());
+            Ok::<_, ()>(())
+        }).wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_byte_gate_without_limit() {
+        let inflight_bytes = Arc::new(AtomicUsize::new(usize::max_value()));
+        let mut gate = ByteGate::new(stream::iter_ok::<_, ()>(vec![1]), inflight_bytes, None);
+
+        future::lazy(|| {
+            assert_eq!(Ok(Async::Ready(Some(1))), gate.poll());
+            Ok::<_, ()>(())
+        }).wait()
+        .unwrap();
+    }
 }
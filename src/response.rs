@@ -1,9 +1,29 @@
+use std::collections::HashMap;
+use std::fs;
+
+use failure::ResultExt;
 use serde_json;
 
-#[derive(Debug, Serialize)]
+use Result;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TaggedResponse {
     pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_url: Option<String>,
     pub response: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_meters: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_seconds: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_in_traffic_seconds: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<Vec<Vec<Step>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<Attempts>,
 }
 
 impl TaggedResponse {
@@ -17,10 +37,583 @@ impl TaggedResponse {
             })
         });
 
+        // Summarise the first route of a successful response
+        let ok = response["status"] == "OK";
+        let sum = |field| if ok { sum_legs(&response, field) } else { None };
+        let distance_meters = sum("distance");
+        let duration_seconds = sum("duration");
+        let duration_in_traffic_seconds = sum("duration_in_traffic");
+
+        // Lift any explanation of an unsuccessful status
+        let error_message = response["error_message"].as_str().map(str::to_string);
+
         // Construct TaggedResponse
         TaggedResponse {
             id: id.to_string(),
+            request_url: None,
             response,
+            distance_meters,
+            duration_seconds,
+            duration_in_traffic_seconds,
+            error_message,
+            steps: None,
+            attempts: None,
+        }
+    }
+
+    /// Build a response for a request that received an unsuccessful HTTP
+    /// status code.
+    pub fn http_error(id: &str, code: u16) -> TaggedResponse {
+        let message = format!("HTTP status {} received from server.", code);
+        TaggedResponse {
+            id: id.to_string(),
+            request_url: None,
+            response: json!({
+                "error_message": message,
+                "http_status": code,
+                "routes": [],
+                "status": "HTTP_ERROR"
+            }),
+            distance_meters: None,
+            duration_seconds: None,
+            duration_in_traffic_seconds: None,
+            error_message: Some(message),
+            steps: None,
+            attempts: None,
+        }
+    }
+
+    /// Build a response for a request that could not be completed, such as
+    /// after a network error.
+    pub fn request_error(id: &str, message: &str) -> TaggedResponse {
+        TaggedResponse {
+            id: id.to_string(),
+            request_url: None,
+            response: json!({
+                "error_message": message,
+                "routes": [],
+                "status": "REQUEST_FAILED"
+            }),
+            distance_meters: None,
+            duration_seconds: None,
+            duration_in_traffic_seconds: None,
+            error_message: Some(message.to_string()),
+            steps: None,
+            attempts: None,
         }
     }
+
+    /// Build a response for a request that was not sent, or did not finish,
+    /// before the deadline for the run.
+    pub fn skipped(id: &str) -> TaggedResponse {
+        let message = "Deadline reached before a response was received.";
+        TaggedResponse {
+            id: id.to_string(),
+            request_url: None,
+            response: json!({
+                "error_message": message,
+                "routes": [],
+                "status": "SKIPPED"
+            }),
+            distance_meters: None,
+            duration_seconds: None,
+            duration_in_traffic_seconds: None,
+            error_message: Some(message.to_string()),
+            steps: None,
+            attempts: None,
+        }
+    }
+
+    pub fn with_attempts(mut self, attempts: Attempts) -> TaggedResponse {
+        self.attempts = Some(attempts);
+        self
+    }
+
+    /// The status of the response, e.g. "OK".
+    pub fn status(&self) -> &str {
+        self.response["status"].as_str().unwrap_or("UNKNOWN")
+    }
+
+    /// Whether the request was resolved successfully, even if no route was
+    /// found.
+    pub fn is_success(&self) -> bool {
+        SUCCESS_STATUSES.contains(&self.status())
+    }
+
+    pub fn with_request_url(mut self, url: &str) -> TaggedResponse {
+        self.request_url = Some(url.to_string());
+        self
+    }
+
+    /// Extract the steps of each leg of the first route, which are left
+    /// empty unless the status is "OK".
+    pub fn with_steps(mut self) -> TaggedResponse {
+        self.steps = Some(if self.status() == "OK" {
+            extract_steps(&self.response)
+        } else {
+            Vec::new()
+        });
+        self
+    }
+}
+
+/// A single turn-by-turn step of a route leg.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Step {
+    pub instruction: String,
+    pub distance_meters: Option<i64>,
+    pub duration_seconds: Option<i64>,
+    pub polyline: Option<String>,
+}
+
+impl Step {
+    fn from_value(step: &serde_json::Value) -> Step {
+        Step {
+            instruction: step["html_instructions"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            distance_meters: step["distance"]["value"].as_i64(),
+            duration_seconds: step["duration"]["value"].as_i64(),
+            polyline: step["polyline"]["points"].as_str().map(str::to_string),
+        }
+    }
+}
+
+/// Collect the steps of each leg of the first route, in order.
+fn extract_steps(response: &serde_json::Value) -> Vec<Vec<Step>> {
+    let legs = match response["routes"][0]["legs"].as_array() {
+        Some(legs) => legs,
+        None => return Vec::new(),
+    };
+    legs.iter()
+        .map(|leg| match leg["steps"].as_array() {
+            Some(steps) => steps.iter().map(Step::from_value).collect(),
+            None => Vec::new(),
+        }).collect()
+}
+
+/// Sum the values of the given field, such as "distance", over the legs of
+/// the first route. Returns `None` if any leg lacks the field.
+fn sum_legs(response: &serde_json::Value, field: &str) -> Option<i64> {
+    let legs = response["routes"][0]["legs"].as_array()?;
+    let mut total = 0;
+    for leg in legs {
+        total += leg[field]["value"].as_i64()?;
+    }
+    Some(total)
+}
+
+/// Statuses that indicate a request was resolved successfully, even if no
+/// route was found.
+const SUCCESS_STATUSES: [&str; 2] = ["OK", "ZERO_RESULTS"];
+
+/// A category of failed responses, along with the ids and error messages of
+/// every response in it.
+#[derive(Debug, PartialEq)]
+pub struct ErrorGroup {
+    pub category: String,
+    pub failures: Vec<(String, Option<String>)>,
+}
+
+/// Group unsuccessful responses by their status, in order of first
+/// appearance.
+pub fn group_errors(responses: &[TaggedResponse]) -> Vec<ErrorGroup> {
+    let mut groups: Vec<ErrorGroup> = Vec::new();
+    for response in responses {
+        if response.is_success() {
+            continue;
+        }
+        let status = response.status();
+
+        let failure = (response.id.clone(), response.error_message.clone());
+        match groups.iter().position(|g| g.category == status) {
+            Some(pos) => groups[pos].failures.push(failure),
+            None => groups.push(ErrorGroup {
+                category: status.to_string(),
+                failures: vec![failure],
+            }),
+        }
+    }
+    groups
+}
+
+/// Count responses by their status, in order of first appearance.
+pub fn count_statuses(responses: &[TaggedResponse]) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for response in responses {
+        let status = response.status();
+        match counts.iter().position(|c| c.0 == status) {
+            Some(pos) => counts[pos].1 += 1,
+            None => counts.push((status.to_string(), 1)),
+        }
+    }
+    counts
+}
+
+/// Count the responses that were not resolved successfully.
+pub fn count_failures(responses: &[TaggedResponse]) -> usize {
+    responses.iter().filter(|r| !r.is_success()).count()
+}
+
+/// Load the responses with an OK status from a previous output file, keyed
+/// by id, so that their requests need not be sent again.
+pub fn load_completed(path: &str) -> Result<HashMap<String, TaggedResponse>> {
+    let contents = fs::read_to_string(path).context(ResponseError::ReadResume {
+        path: path.to_string(),
+    })?;
+    let completed = read_completed(&contents).context(ResponseError::InvalidResume {
+        path: path.to_string(),
+    })?;
+    Ok(completed)
+}
+
+fn read_completed(inp: &str) -> Result<HashMap<String, TaggedResponse>> {
+    let responses = serde_json::from_str::<Vec<TaggedResponse>>(inp)?;
+    let mut completed = HashMap::new();
+    for response in responses {
+        if response.response["status"] == "OK" {
+            completed.entry(response.id.clone()).or_insert(response);
+        }
+    }
+    Ok(completed)
+}
+
+/// Combine previously completed responses with fresh ones, in the order of
+/// the given ids. Fresh responses must be in the order of the ids that were
+/// not previously completed.
+pub fn merge_completed(
+    ids: &[String],
+    completed: &HashMap<String, TaggedResponse>,
+    fresh: Vec<TaggedResponse>,
+) -> Vec<TaggedResponse> {
+    let mut fresh = fresh.into_iter();
+    ids.iter()
+        .filter_map(|id| match completed.get(id) {
+            Some(response) => Some(response.clone()),
+            None => fresh.next(),
+        }).collect()
+}
+
+/// The history of attempts made to resolve a single request.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Attempts {
+    pub count: u32,
+    pub failures: Vec<String>,
+}
+
+impl Attempts {
+    pub fn new() -> Attempts {
+        Attempts::default()
+    }
+
+    /// Record an attempt that failed, along with the status or error that
+    /// caused it to fail.
+    pub fn record_failure(&mut self, reason: &str) {
+        self.count += 1;
+        self.failures.push(reason.to_string());
+    }
+
+    /// Record the final, successful attempt.
+    pub fn record_success(&mut self) {
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Fail)]
+enum ResponseError {
+    #[fail(display = "could not read resume file ({})", path)]
+    ReadResume { path: String },
+    #[fail(display = "resume file is not a valid output file ({})", path)]
+    InvalidResume { path: String },
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_responses() {
+        let res = TaggedResponse::http_error("1", 503);
+        assert_eq!("HTTP_ERROR", res.response["status"]);
+        assert_eq!(503, res.response["http_status"]);
+
+        let res = TaggedResponse::request_error("2", "connection reset");
+        assert_eq!("REQUEST_FAILED", res.response["status"]);
+        assert_eq!("connection reset", res.response["error_message"]);
+        assert_eq!(Some("connection reset".to_string()), res.error_message);
+
+        let res = TaggedResponse::skipped("3");
+        assert_eq!("SKIPPED", res.status());
+        assert!(!res.is_success());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let res = TaggedResponse::new(
+            "1",
+            r#"{"status": "REQUEST_DENIED", "error_message": "The provided API key is invalid."}"#,
+        );
+        assert_eq!(
+            Some("The provided API key is invalid.".to_string()),
+            res.error_message
+        );
+
+        let res = TaggedResponse::new("2", "<html>");
+        assert_eq!(
+            Some("Malformed JSON received from server.".to_string()),
+            res.error_message
+        );
+
+        let res = TaggedResponse::new("3", r#"{"status": "OK"}"#);
+        assert_eq!(None, res.error_message);
+        assert_eq!(
+            json!({"id": "3", "response": {"status": "OK"}}),
+            serde_json::to_value(&res).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_route_summary() {
+        let res = TaggedResponse::new(
+            "1",
+            r#"{"status": "OK", "routes": [{"legs": [
+                {"distance": {"value": 1200}, "duration": {"value": 300}},
+                {"distance": {"value": 800}, "duration": {"value": 120}}
+            ]}]}"#,
+        );
+        assert_eq!(Some(2000), res.distance_meters);
+        assert_eq!(Some(420), res.duration_seconds);
+        assert_eq!(None, res.duration_in_traffic_seconds);
+
+        let res = TaggedResponse::new(
+            "2",
+            r#"{"status": "OK", "routes": [{"legs": [
+                {"distance": {"value": 1200}, "duration": {"value": 300},
+                 "duration_in_traffic": {"value": 360}}
+            ]}]}"#,
+        );
+        assert_eq!(Some(360), res.duration_in_traffic_seconds);
+        assert_eq!(
+            json!({
+                "id": "2",
+                "response": res.response.clone(),
+                "distance_meters": 1200,
+                "duration_seconds": 300,
+                "duration_in_traffic_seconds": 360
+            }),
+            serde_json::to_value(&res).unwrap()
+        );
+
+        // Summaries are only taken from successful responses
+        let res = TaggedResponse::new(
+            "3",
+            r#"{"status": "NOT_FOUND", "routes": [{"legs": [
+                {"distance": {"value": 1200}, "duration": {"value": 300}}
+            ]}]}"#,
+        );
+        assert_eq!(None, res.distance_meters);
+
+        let res = TaggedResponse::new("4", r#"{"status": "OK", "routes": [{"legs": "none"}]}"#);
+        assert_eq!(None, res.duration_seconds);
+    }
+
+    #[test]
+    fn test_attempts_succeeding_on_third_attempt() {
+        let mut attempts = Attempts::new();
+        attempts.record_failure("OVER_QUERY_LIMIT");
+        attempts.record_failure("connection reset");
+        attempts.record_success();
+
+        let res = TaggedResponse::new("1", r#"{"status": "OK"}"#).with_attempts(attempts);
+
+        assert_eq!(
+            json!({
+                "id": "1",
+                "response": {"status": "OK"},
+                "attempts": {
+                    "count": 3,
+                    "failures": ["OVER_QUERY_LIMIT", "connection reset"]
+                }
+            }),
+            serde_json::to_value(&res).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_count_statuses() {
+        let responses = vec![
+            TaggedResponse::new("1", r#"{"status": "OK"}"#),
+            TaggedResponse::new("2", r#"{"status": "ZERO_RESULTS"}"#),
+            TaggedResponse::new("3", r#"{"status": "OK"}"#),
+            TaggedResponse::new("4", "<html>"),
+            TaggedResponse::new("5", r#"{"status": "NOT_FOUND"}"#),
+        ];
+
+        assert_eq!(
+            vec![
+                ("OK".to_string(), 2),
+                ("ZERO_RESULTS".to_string(), 1),
+                ("MALFORMED_JSON".to_string(), 1),
+                ("NOT_FOUND".to_string(), 1),
+            ],
+            count_statuses(&responses)
+        );
+        assert_eq!(2, count_failures(&responses));
+    }
+
+    #[test]
+    fn test_is_success() {
+        assert!(TaggedResponse::new("1", r#"{"status": "OK"}"#).is_success());
+        assert!(TaggedResponse::new("2", r#"{"status": "ZERO_RESULTS"}"#).is_success());
+        assert!(!TaggedResponse::new("3", r#"{"status": "NOT_FOUND"}"#).is_success());
+        assert!(!TaggedResponse::request_error("4", "connection reset").is_success());
+
+        let res = TaggedResponse::new("5", r#"{"routes": []}"#);
+        assert_eq!("UNKNOWN", res.status());
+        assert!(!res.is_success());
+    }
+
+    #[test]
+    fn test_group_errors() {
+        let responses = vec![
+            TaggedResponse::new("1", r#"{"status": "OK"}"#),
+            TaggedResponse::new("2", r#"{"status": "ZERO_RESULTS"}"#),
+            TaggedResponse::new("3", r#"{"status": "NOT_FOUND"}"#),
+            TaggedResponse::new("4", "<html>"),
+            TaggedResponse::new("5", r#"{"status": "NOT_FOUND"}"#),
+        ];
+
+        let res = group_errors(&responses);
+        assert_eq!(
+            vec![
+                ErrorGroup {
+                    category: "NOT_FOUND".to_string(),
+                    failures: vec![("3".to_string(), None), ("5".to_string(), None)],
+                },
+                ErrorGroup {
+                    category: "MALFORMED_JSON".to_string(),
+                    failures: vec![(
+                        "4".to_string(),
+                        Some("Malformed JSON received from server.".to_string())
+                    )],
+                },
+            ],
+            res
+        );
+    }
+
+    #[test]
+    fn test_request_url() {
+        let res = TaggedResponse::new("1", r#"{"status": "NOT_FOUND"}"#)
+            .with_request_url("https://example.com/?origin=a");
+
+        assert_eq!(
+            json!({
+                "id": "1",
+                "request_url": "https://example.com/?origin=a",
+                "response": {"status": "NOT_FOUND"}
+            }),
+            serde_json::to_value(&res).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_steps() {
+        let res = TaggedResponse::new(
+            "1",
+            r#"{"status": "OK", "routes": [{"legs": [
+                {"steps": [
+                    {"html_instructions": "Head <b>west</b>", "distance": {"value": 120},
+                     "duration": {"value": 30}, "polyline": {"points": "nd~eFuqwtZ"}},
+                    {"html_instructions": "Turn <b>left</b>", "distance": {"value": 80},
+                     "duration": {"value": 20}, "polyline": {"points": "xd~eF_swtZ"}}
+                ]},
+                {"steps": [{"html_instructions": "Continue"}]}
+            ]}]}"#,
+        ).with_steps();
+        assert_eq!(
+            Some(vec![
+                vec![
+                    Step {
+                        instruction: "Head <b>west</b>".to_string(),
+                        distance_meters: Some(120),
+                        duration_seconds: Some(30),
+                        polyline: Some("nd~eFuqwtZ".to_string()),
+                    },
+                    Step {
+                        instruction: "Turn <b>left</b>".to_string(),
+                        distance_meters: Some(80),
+                        duration_seconds: Some(20),
+                        polyline: Some("xd~eF_swtZ".to_string()),
+                    },
+                ],
+                vec![Step {
+                    instruction: "Continue".to_string(),
+                    distance_meters: None,
+                    duration_seconds: None,
+                    polyline: None,
+                }],
+            ]),
+            res.steps
+        );
+
+        // Steps are only taken from successful responses
+        let res = TaggedResponse::new(
+            "2",
+            r#"{"status": "NOT_FOUND", "routes": [{"legs": [
+                {"steps": [{"html_instructions": "Head west"}]}
+            ]}]}"#,
+        ).with_steps();
+        assert_eq!(Some(Vec::new()), res.steps);
+        let res = TaggedResponse::request_error("3", "timed out").with_steps();
+        assert_eq!(json!([]), serde_json::to_value(&res).unwrap()["steps"]);
+    }
+
+    #[test]
+    fn test_attempts_omitted_by_default() {
+        let res = TaggedResponse::new("1", r#"{"status": "OK"}"#);
+
+        assert_eq!(
+            json!({"id": "1", "response": {"status": "OK"}}),
+            serde_json::to_value(&res).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_completed() {
+        let inp = r#"[
+            {"id": "1", "response": {"status": "OK"}},
+            {"id": "2", "response": {"status": "OVER_QUERY_LIMIT"}},
+            {"id": "3", "response": {"status": "OK"}, "attempts": {"count": 2, "failures": []}}
+        ]"#;
+
+        let res = read_completed(inp).unwrap();
+        let mut ids = res.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec!["1".to_string(), "3".to_string()], ids);
+
+        assert!(read_completed(r#"{"id": "1"}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge_completed() {
+        let mut completed = HashMap::new();
+        completed.insert(
+            "2".to_string(),
+            TaggedResponse::new("2", r#"{"status": "OK"}"#),
+        );
+        let fresh = vec![
+            TaggedResponse::new("1", r#"{"status": "OK"}"#),
+            TaggedResponse::new("3", r#"{"status": "NOT_FOUND"}"#),
+        ];
+        let ids = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+
+        let res = merge_completed(&ids, &completed, fresh)
+            .into_iter()
+            .map(|r| r.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, res);
+    }
 }
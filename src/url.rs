@@ -5,39 +5,99 @@ use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha1::Sha1;
 use failure::ResultExt;
+use rayon::prelude::*;
 use reqwest::Url;
 
 use config::Credentials;
-use query::Query;
+use query::{Mode, Query};
 use Result;
 
-const DOMAIN: &str = "https://maps.googleapis.com";
+/// The scheme and host that requests are sent to unless overridden.
+pub const DEFAULT_BASE_URL: &str = "https://maps.googleapis.com";
 const PATH: &str = "/maps/api/directions/json";
 
+/// Number of queries above which URLs are built in parallel, where the cost
+/// of signing outweighs that of spreading the work across threads.
+const PARALLEL_THRESHOLD: usize = 10_000;
+
+/// Query parameters that hold credentials, removed from URLs that are
+/// written to output.
+const SECRET_PARAMETERS: [&str; 2] = ["key", "signature"];
+
 #[derive(Debug, PartialEq)]
 pub struct TaggedUrl {
     pub id: String,
+    pub mode: Mode,
     pub url: Url,
 }
 
 impl TaggedUrl {
-    pub fn new(query: &Query, credentials: &Credentials) -> Result<TaggedUrl> {
+    /// Build the request URL for a query, sent to the given base URL, e.g.
+    /// `DEFAULT_BASE_URL`, with coordinates given to `precision` decimal
+    /// places.
+    pub fn new(
+        query: &Query,
+        credentials: &Credentials,
+        base_url: &str,
+        precision: usize,
+    ) -> Result<TaggedUrl> {
+        let base_url = base_url.trim_right_matches('/');
         let tagged_url = match credentials {
-            Credentials::Normal { api_key } => build_normal_url(query, api_key)?,
+            Credentials::Normal { api_key } => {
+                build_normal_url(query, precision, api_key, base_url)
+            }
             Credentials::Premium {
                 client_id,
                 private_key,
                 channel,
             } => build_premium_url(
                 query,
+                precision,
                 client_id,
                 private_key,
                 channel.as_ref().map(String::as_str),
-            )?,
-        };
+                base_url,
+            ),
+        }.context(UrlError::Query {
+            id: query.id.clone(),
+        })?;
 
         Ok(tagged_url)
     }
+
+    /// The request URL without the API key or signature, so that it can be
+    /// shared or logged safely.
+    pub fn redacted_url(&self) -> Url {
+        // Filter the raw parameters rather than decoding them, so that the
+        // rest of the URL is exactly as sent
+        let query = self
+            .url
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|p| !SECRET_PARAMETERS.contains(&p.splitn(2, '=').next().unwrap_or("")))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut url = self.url.clone();
+        url.set_query(Some(&query));
+        url
+    }
+}
+
+/// Build the request URL for each query, as for `TaggedUrl::new`, keeping
+/// them in the same order. Large batches are built in parallel.
+pub fn build_urls(
+    queries: &[Query],
+    credentials: &Credentials,
+    base_url: &str,
+    precision: usize,
+) -> Result<Vec<TaggedUrl>> {
+    let build = |q| TaggedUrl::new(q, credentials, base_url, precision);
+    if queries.len() > PARALLEL_THRESHOLD {
+        queries.par_iter().map(build).collect()
+    } else {
+        queries.iter().map(build).collect()
+    }
 }
 
 fn parse_url(inp: &str) -> Result<Url> {
@@ -46,13 +106,19 @@ fn parse_url(inp: &str) -> Result<Url> {
     })?)
 }
 
-fn build_normal_url(query: &Query, api_key: &str) -> Result<TaggedUrl> {
-    let query_string = query.to_string();
+fn build_normal_url(
+    query: &Query,
+    precision: usize,
+    api_key: &str,
+    base_url: &str,
+) -> Result<TaggedUrl> {
+    let query_string = format!("{:.*}", precision, query);
     let key_parameter = format!("key={}", api_key);
-    let uri_str = format!("{}{}?{}&{}", DOMAIN, PATH, query_string, key_parameter);
+    let uri_str = format!("{}{}?{}&{}", base_url, PATH, query_string, key_parameter);
 
     let tagged_url = TaggedUrl {
         id: query.id.clone(),
+        mode: query.mode,
         url: parse_url(&uri_str)?,
     };
     Ok(tagged_url)
@@ -60,11 +126,13 @@ fn build_normal_url(query: &Query, api_key: &str) -> Result<TaggedUrl> {
 
 fn build_premium_url(
     query: &Query,
+    precision: usize,
     client_id: &str,
     private_key: &str,
     channel: Option<&str>,
+    base_url: &str,
 ) -> Result<TaggedUrl> {
-    let query_string = query.to_string();
+    let query_string = format!("{:.*}", precision, query);
     let client_id_parameter = format!("client={}", client_id);
     let path_and_query = match channel {
         Some(channel) => {
@@ -77,13 +145,16 @@ fn build_premium_url(
         None => format!("{}?{}&{}", PATH, query_string, client_id_parameter),
     };
 
+    // Only the path and query are signed, so the base URL can be changed
+    // without invalidating the signature
     let signature = sign_url(&path_and_query, private_key)?;
     let signature_parameter = format!("signature={}", signature);
 
-    let url_str = format!("{}{}&{}", DOMAIN, path_and_query, signature_parameter);
+    let url_str = format!("{}{}&{}", base_url, path_and_query, signature_parameter);
 
     let tagged_url = TaggedUrl {
         id: query.id.clone(),
+        mode: query.mode,
         url: parse_url(&url_str)?,
     };
     Ok(tagged_url)
@@ -101,6 +172,8 @@ fn sign_url(path_and_query: &str, private_key: &str) -> Result<String> {
 enum UrlError {
     #[fail(display = "could not parse given URL string ({})", inv)]
     InvalidUrl { inv: String },
+    #[fail(display = "could not build request URL for id ({})", id)]
+    Query { id: String },
 }
 
 #[cfg(test)]
@@ -123,60 +196,113 @@ mod tests {
     fn test_build_normal_url() {
         let query = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1537308000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         };
         let api_key = "test_key";
 
         let res_url = parse_url("https://maps.googleapis.com/maps/api/directions/json?origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1537308000&mode=driving&avoid=tolls&traffic_model=best_guess&key=test_key").unwrap();
         let res = TaggedUrl {
             id: query.id.clone(),
+            mode: query.mode,
             url: res_url,
         };
 
-        assert_eq!(res, build_normal_url(&query, api_key).unwrap());
+        assert_eq!(
+            res,
+            build_normal_url(&query, DEFAULT_COORD_PRECISION, api_key, DEFAULT_BASE_URL).unwrap()
+        );
     }
 
     #[test]
     fn test_build_premium_url() {
         let query = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1537308000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         };
         let client_id = "clientID";
         let private_key = "vNIXE0xscrmjlyV-12Nj_BvUPaw=";
 
-        let res_url = parse_url("https://maps.googleapis.com/maps/api/directions/json?origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1537308000&mode=driving&avoid=tolls&traffic_model=best_guess&client=clientID&signature=PGyz3IR_yXL9_4MSks6uMClHDQ8=").unwrap();
-        let res = TaggedUrl {
-            id: query.id.clone(),
-            url: res_url,
-        };
+        // The precision of coordinates changes the signed string
+        let cases = vec![
+            (
+                DEFAULT_COORD_PRECISION,
+                "origin=-37.820189,145.149954&destination=-37.819681,144.952302",
+                "PGyz3IR_yXL9_4MSks6uMClHDQ8=",
+            ),
+            (
+                4,
+                "origin=-37.8202,145.1500&destination=-37.8197,144.9523",
+                "48x1BQ_5n2XEJx_LKNkiWpJ7-bs=",
+            ),
+            (
+                9,
+                "origin=-37.820189000,145.149954000&destination=-37.819681000,144.952302000",
+                "6DlzrHOD4tesZOtJ8n3qYoCBgFg=",
+            ),
+        ];
+        for (precision, coords, signature) in cases {
+            let res_url = parse_url(&format!("https://maps.googleapis.com/maps/api/directions/json?{}&departure_time=1537308000&mode=driving&avoid=tolls&traffic_model=best_guess&client=clientID&signature={}", coords, signature)).unwrap();
+            let res = TaggedUrl {
+                id: query.id.clone(),
+                mode: query.mode,
+                url: res_url,
+            };
 
-        assert_eq!(
-            res,
-            build_premium_url(&query, client_id, private_key, Option::None).unwrap()
-        );
+            assert_eq!(
+                res,
+                build_premium_url(
+                    &query,
+                    precision,
+                    client_id,
+                    private_key,
+                    Option::None,
+                    DEFAULT_BASE_URL
+                ).unwrap()
+            );
+        }
     }
 
     #[test]
     fn test_build_premium_url_with_channel() {
         let query = Query {
             id: "1".to_string(),
-            origin: Coord::new(-37.820189, 145.149954).unwrap(),
-            destination: Coord::new(-37.819681, 144.952302).unwrap(),
-            departure_time: DepartureTime::new(1537308000).unwrap(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
             mode: Mode::Driving,
             avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
             traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
         };
         let client_id = "clientID";
         let private_key = "vNIXE0xscrmjlyV-12Nj_BvUPaw=";
@@ -185,12 +311,190 @@ mod tests {
         let res_url = parse_url("https://maps.googleapis.com/maps/api/directions/json?origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1537308000&mode=driving&avoid=tolls&traffic_model=best_guess&client=clientID&channel=CHANNEL&signature=ewwxwGeX8iEt5K0NclwinyqEeLc=").unwrap();
         let res = TaggedUrl {
             id: query.id.clone(),
+            mode: query.mode,
             url: res_url,
         };
 
         assert_eq!(
             res,
-            build_premium_url(&query, client_id, private_key, channel).unwrap()
+            build_premium_url(
+                &query,
+                DEFAULT_COORD_PRECISION,
+                client_id,
+                private_key,
+                channel,
+                DEFAULT_BASE_URL
+            ).unwrap()
+        );
+    }
+
+    /// The signature must be computed over exactly the path and query that
+    /// are sent, including any place IDs.
+    #[test]
+    fn test_build_premium_url_with_place_id() {
+        let query = Query {
+            id: "1".to_string(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::PlaceId("ChIJgf0RD69C1moR4OeMIXVWBAU".to_string()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            mode: Mode::Walking,
+            avoidances: Option::None,
+            traffic_model: Option::None,
+            waypoints: Option::Some("place_id:ChIJP3Sa8ziYEmsRUKgyFmh9AQM".parse().unwrap()),
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
+        };
+        let private_key = "vNIXE0xscrmjlyV-12Nj_BvUPaw=";
+
+        let res = build_premium_url(
+            &query,
+            DEFAULT_COORD_PRECISION,
+            "clientID",
+            private_key,
+            Option::None,
+            DEFAULT_BASE_URL,
+        ).unwrap();
+        let url = res.url.as_str();
+        let signature_start = url.find("&signature=").unwrap();
+        let path_and_query = &url[DEFAULT_BASE_URL.len()..signature_start];
+
+        assert!(path_and_query.contains("destination=place_id:ChIJgf0RD69C1moR4OeMIXVWBAU"));
+        assert!(path_and_query.contains("waypoints=place_id:ChIJP3Sa8ziYEmsRUKgyFmh9AQM"));
+        assert_eq!(
+            sign_url(path_and_query, private_key).unwrap(),
+            &url[signature_start + "&signature=".len()..]
+        );
+    }
+
+    #[test]
+    fn test_redacted_url() {
+        let query = Query {
+            id: "1".to_string(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            mode: Mode::Walking,
+            avoidances: Option::None,
+            traffic_model: Option::None,
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
+        };
+        let expected = "https://maps.googleapis.com/maps/api/directions/json?origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1537308000&mode=walking";
+
+        let normal =
+            build_normal_url(&query, DEFAULT_COORD_PRECISION, "test_key", DEFAULT_BASE_URL)
+                .unwrap();
+        assert_eq!(expected, normal.redacted_url().as_str());
+
+        let premium = build_premium_url(
+            &query,
+            DEFAULT_COORD_PRECISION,
+            "clientID",
+            "vNIXE0xscrmjlyV-12Nj_BvUPaw=",
+            Option::None,
+            DEFAULT_BASE_URL,
+        ).unwrap();
+        assert_eq!(
+            format!("{}&client=clientID", expected),
+            premium.redacted_url().as_str()
+        );
+    }
+
+    /// URLs are built in input order whether or not they are built in
+    /// parallel, and an unsignable query is reported rather than panicking.
+    #[test]
+    fn test_build_urls() {
+        let query = |id: usize| Query {
+            id: id.to_string(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            mode: Mode::Walking,
+            avoidances: Option::None,
+            traffic_model: Option::None,
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
+        };
+        let credentials = Credentials::Premium {
+            client_id: "clientID".to_string(),
+            private_key: "vNIXE0xscrmjlyV-12Nj_BvUPaw=".to_string(),
+            channel: Option::None,
+        };
+
+        for &count in &[3, PARALLEL_THRESHOLD + 1] {
+            let queries = (0..count).map(query).collect::<Vec<_>>();
+            let res =
+                build_urls(&queries, &credentials, DEFAULT_BASE_URL, DEFAULT_COORD_PRECISION)
+                    .unwrap();
+            assert_eq!(count, res.len());
+            assert!(res.iter().enumerate().all(|(i, r)| r.id == i.to_string()));
+        }
+
+        let credentials = Credentials::Premium {
+            client_id: "clientID".to_string(),
+            private_key: "not base64!".to_string(),
+            channel: Option::None,
+        };
+        let res = build_urls(
+            &[query(1)],
+            &credentials,
+            DEFAULT_BASE_URL,
+            DEFAULT_COORD_PRECISION,
+        );
+        assert_eq!(
+            "could not build request URL for id (1)",
+            res.unwrap_err().to_string()
+        );
+    }
+
+    /// Overriding the base URL changes the host but not the signature.
+    #[test]
+    fn test_tagged_url_with_base_url() {
+        let query = Query {
+            id: "1".to_string(),
+            origin: Location::Coords(Coord::new(-37.820189, 145.149954).unwrap()),
+            destination: Location::Coords(Coord::new(-37.819681, 144.952302).unwrap()),
+            time: TimeConstraint::Departure(DepartureTime::new(1537308000).unwrap()),
+            mode: Mode::Driving,
+            avoidances: Option::Some(Avoidances::new(&vec![Avoidance::Tolls])),
+            traffic_model: Option::Some(TrafficModel::BestGuess),
+            waypoints: Option::None,
+            units: Option::None,
+            language: Option::None,
+            region: Option::None,
+            transit_modes: Option::None,
+            transit_routing_preference: Option::None,
+            alternatives: false,
+        };
+        let credentials = Credentials::Premium {
+            client_id: "clientID".to_string(),
+            private_key: "vNIXE0xscrmjlyV-12Nj_BvUPaw=".to_string(),
+            channel: Option::None,
+        };
+
+        let res = TaggedUrl::new(
+            &query,
+            &credentials,
+            "http://localhost:8080/",
+            DEFAULT_COORD_PRECISION,
+        ).unwrap();
+        assert_eq!(
+            "http://localhost:8080/maps/api/directions/json?origin=-37.820189,145.149954&destination=-37.819681,144.952302&departure_time=1537308000&mode=driving&avoid=tolls&traffic_model=best_guess&client=clientID&signature=PGyz3IR_yXL9_4MSks6uMClHDQ8=",
+            res.url.as_str()
         );
     }
 }